@@ -0,0 +1,162 @@
+//! Load generator for a running server.
+//!
+//! ```
+//! cargo run --release --example login_bench -- [login|user] [concurrency] [requests]
+//! ```
+//!
+//! Every client thread sends its share of `requests` to `POST /api/login` (or `GET /api/user`
+//! with the access token of a bench account), while a probe thread keeps calling the cheap
+//! `GET /api/` to show how responsive the workers stay under that load.
+//!
+//! All requests share one address and one account, start the server without the `/login` rate
+//! limits (`RATE_LIMIT_LOGIN_IP=off RATE_LIMIT_LOGIN_EMAIL=off`) or raise them, like
+//! `RATE_LIMIT_LOGIN_EMAIL=100000/1`.
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const EMAIL: &str = "bench@example.com";
+const PASSWORD: &str = "bench-password";
+
+fn main() {
+    dotenv::dotenv().ok();
+    let address = std::env::var("ADDRESS").expect("Missed 'ADDRESS' environment variable");
+
+    let args: Vec<String> = std::env::args().collect();
+    let scenario = args.get(1).cloned().unwrap_or_else(|| "login".to_string());
+    let concurrency: usize = args.get(2).and_then(|s| s.parse().ok()).unwrap_or(16);
+    let requests: usize = args.get(3).and_then(|s| s.parse().ok()).unwrap_or(200);
+
+    let credentials = format!(r#"{{"email":"{EMAIL}","password":"{PASSWORD}"}}"#);
+    let registration = format!(
+        r#"{{"first_name":"Bench","last_name":"Bench","email":"{EMAIL}","password":"{PASSWORD}","password_confirm":"{PASSWORD}"}}"#
+    );
+    // The account may already exist from a previous run
+    send(&address, "POST", "/api/register", None, Some(&registration));
+
+    let (status, body) = send(&address, "POST", "/api/login", None, Some(&credentials));
+    assert_eq!(status, 200, "bench login failed: {body}");
+    let access_token = body
+        .split(r#""token":""#)
+        .nth(1)
+        .and_then(|rest| rest.split('"').next())
+        .expect("no token in login response")
+        .to_string();
+
+    let running = Arc::new(AtomicBool::new(true));
+    let probe = {
+        let address = address.clone();
+        let running = running.clone();
+        thread::spawn(move || {
+            let mut latencies = Vec::new();
+            while running.load(Ordering::Relaxed) {
+                let start = Instant::now();
+                send(&address, "GET", "/api/", None, None);
+                latencies.push(start.elapsed());
+                thread::sleep(Duration::from_millis(10));
+            }
+            latencies
+        })
+    };
+
+    let start = Instant::now();
+    let clients: Vec<_> = (0..concurrency)
+        .map(|i| {
+            let address = address.clone();
+            let scenario = scenario.clone();
+            let credentials = credentials.clone();
+            let access_token = access_token.clone();
+            let share = requests / concurrency + usize::from(i < requests % concurrency);
+            thread::spawn(move || {
+                let mut latencies = Vec::with_capacity(share);
+                let mut failures = 0;
+                for _ in 0..share {
+                    let request_start = Instant::now();
+                    let (status, _) = match scenario.as_str() {
+                        "user" => send(&address, "GET", "/api/user", Some(&access_token), None),
+                        _ => send(&address, "POST", "/api/login", None, Some(&credentials)),
+                    };
+                    latencies.push(request_start.elapsed());
+                    if status != 200 {
+                        failures += 1;
+                    }
+                }
+                (latencies, failures)
+            })
+        })
+        .collect();
+
+    let mut latencies = Vec::with_capacity(requests);
+    let mut failures = 0;
+    for client in clients {
+        let (client_latencies, client_failures) = client.join().unwrap();
+        latencies.extend(client_latencies);
+        failures += client_failures;
+    }
+    let elapsed = start.elapsed();
+    running.store(false, Ordering::Relaxed);
+    let probe_latencies = probe.join().unwrap();
+
+    println!("scenario:    {scenario}, {concurrency} clients, {requests} requests");
+    println!(
+        "throughput:  {:.1} req/s ({failures} failed)",
+        requests as f64 / elapsed.as_secs_f64()
+    );
+    report("latency:    ", latencies);
+    report("probe:      ", probe_latencies);
+}
+
+/// Print the median, 99th percentile and maximum of `latencies`
+fn report(label: &str, mut latencies: Vec<Duration>) {
+    if latencies.is_empty() {
+        return;
+    }
+    latencies.sort();
+    let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100].as_secs_f64() * 1000.0;
+    println!(
+        "{label} p50 {:.1} ms, p99 {:.1} ms, max {:.1} ms",
+        percentile(50),
+        percentile(99),
+        percentile(100)
+    );
+}
+
+/// Send one HTTP/1.1 request and return the status code and body
+fn send(
+    address: &str,
+    method: &str,
+    path: &str,
+    token: Option<&str>,
+    body: Option<&str>,
+) -> (u16, String) {
+    let mut stream = TcpStream::connect(address).expect("could not connect to the server");
+    let mut request =
+        format!("{method} {path} HTTP/1.1\r\nHost: {address}\r\nConnection: close\r\n");
+    if let Some(token) = token {
+        request += &format!("Authorization: Bearer {token}\r\n");
+    }
+    let body = body.unwrap_or_default();
+    if !body.is_empty() {
+        request += "Content-Type: application/json\r\n";
+    }
+    request += &format!("Content-Length: {}\r\n\r\n{body}", body.len());
+    stream.write_all(request.as_bytes()).unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap_or_default();
+    let status = response
+        .split(' ')
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .unwrap_or(0);
+    let body = response
+        .split_once("\r\n\r\n")
+        .map(|(_, body)| body.to_string())
+        .unwrap_or_default();
+
+    (status, body)
+}
@@ -0,0 +1,9 @@
+-- Your SQL goes here
+create table login_link(
+    token varchar primary key not null,
+    email varchar not null,
+    state varchar not null,
+    created_at timestamp not null,
+    expires_at timestamp not null,
+    used_at timestamp
+)
\ No newline at end of file
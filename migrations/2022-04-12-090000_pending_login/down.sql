@@ -0,0 +1,11 @@
+-- This file should undo anything in `up.sql`
+drop table pending_login;
+
+-- user_id is unique again, only the latest session of each user is kept
+delete from user_token older
+    using user_token newer
+    where older.user_id = newer.user_id
+        and (older.created_at, older.token) < (newer.created_at, newer.token);
+
+alter table user_token drop constraint user_token_pkey;
+alter table user_token add primary key (user_id)
\ No newline at end of file
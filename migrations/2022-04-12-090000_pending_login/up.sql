@@ -0,0 +1,14 @@
+-- Your SQL goes here
+-- a user can hold several sessions at once (one per device)
+alter table user_token drop constraint user_token_pkey;
+alter table user_token add primary key (token);
+
+create table pending_login(
+    code varchar primary key not null,
+    state varchar not null,
+    user_id uuid references users (id) on delete cascade on update cascade,
+    created_at timestamp not null,
+    expires_at timestamp not null,
+    approved_at timestamp,
+    claimed_at timestamp
+)
\ No newline at end of file
@@ -0,0 +1,9 @@
+-- Your SQL goes here
+create table trusted_device(
+    id uuid primary key not null,
+    user_id uuid not null references users (id) on delete cascade on update cascade,
+    user_agent varchar not null,
+    created_at timestamp not null,
+    last_seen_at timestamp not null,
+    expires_at timestamp not null
+)
\ No newline at end of file
@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+alter table users drop column email_verified_at
\ No newline at end of file
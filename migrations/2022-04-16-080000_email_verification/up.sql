@@ -0,0 +1,2 @@
+-- Your SQL goes here
+alter table users add column email_verified_at timestamp
\ No newline at end of file
@@ -0,0 +1,19 @@
+-- Your SQL goes here
+-- trimmed, NFC, case-folded email maintained by the application.
+-- SQL has no equivalent of its Unicode case folding, so only ASCII addresses, whose normalization
+-- is exactly trimming and lower-casing, are backfilled here when no other address collides.
+-- `cargo run --bin normalize_emails` fills the other rows the way the application does, reports
+-- the accounts colliding once normalized, to merge by hand. Until then they keep a null
+-- `email_normalized` and are found by their exact email, so the column stays nullable here.
+alter table users add column email_normalized varchar;
+alter table users add constraint users_email_normalized_key unique (email_normalized);
+
+update users set email_normalized = candidate.normalized
+    from (
+        select id,
+            lower(btrim(email, E' \t\n\r\f\x0b')) as normalized,
+            count(*) over (partition by lower(btrim(email, E' \t\n\r\f\x0b'))) as holders
+        from users
+        where email !~ '[^\x01-\x7f]'
+    ) candidate
+    where users.id = candidate.id and candidate.holders = 1
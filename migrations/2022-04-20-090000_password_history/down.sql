@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+drop table password_history
\ No newline at end of file
@@ -0,0 +1,9 @@
+-- Your SQL goes here
+create table password_history(
+    id uuid primary key not null,
+    user_id uuid not null references users (id) on delete cascade on update cascade,
+    password varchar not null,
+    created_at timestamp not null
+);
+
+create index password_history_user_id on password_history (user_id, created_at)
\ No newline at end of file
@@ -0,0 +1,7 @@
+-- This file should undo anything in `up.sql`
+drop index reset_email;
+
+alter table reset drop column used_at;
+alter table reset drop column expires_at;
+alter table reset drop column created_at;
+alter table reset rename column token_hash to token
\ No newline at end of file
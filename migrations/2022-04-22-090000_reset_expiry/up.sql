@@ -0,0 +1,10 @@
+-- Your SQL goes here
+-- Tokens issued before this migration were stored in plaintext and never expire
+delete from reset;
+
+alter table reset rename column token to token_hash;
+alter table reset add column created_at timestamp not null;
+alter table reset add column expires_at timestamp not null;
+alter table reset add column used_at timestamp;
+
+create index reset_email on reset (email)
\ No newline at end of file
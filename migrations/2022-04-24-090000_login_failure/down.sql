@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+drop table login_failure
\ No newline at end of file
@@ -0,0 +1,9 @@
+-- Your SQL goes here
+create table login_failure(
+    key varchar primary key not null,
+    failures integer not null,
+    last_failure_at timestamp not null,
+    blocked_until timestamp,
+    locked_at timestamp,
+    unlock_token_hash varchar unique
+)
\ No newline at end of file
@@ -0,0 +1,9 @@
+-- Your SQL goes here
+create table rate_limit_bucket(
+    key varchar primary key not null,
+    tokens double precision not null,
+    allowed boolean not null,
+    updated_at timestamp not null
+);
+
+create index rate_limit_bucket_updated_at on rate_limit_bucket (updated_at)
\ No newline at end of file
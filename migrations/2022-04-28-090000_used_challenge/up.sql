@@ -0,0 +1,7 @@
+-- Your SQL goes here
+create table used_challenge(
+    jti varchar primary key not null,
+    expires_at timestamp not null
+);
+
+create index used_challenge_expires_at on used_challenge (expires_at)
\ No newline at end of file
@@ -0,0 +1,2 @@
+-- Your SQL goes here
+alter table users add column locale varchar
\ No newline at end of file
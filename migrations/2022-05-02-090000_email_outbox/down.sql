@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+drop table email_outbox
\ No newline at end of file
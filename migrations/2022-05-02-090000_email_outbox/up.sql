@@ -0,0 +1,17 @@
+-- Your SQL goes here
+create table email_outbox(
+    id uuid primary key not null,
+    recipient varchar not null,
+    subject varchar not null,
+    html_body text not null,
+    text_body text not null,
+    attempts int not null default 0,
+    next_attempt_at timestamp not null,
+    last_error varchar,
+    created_at timestamp not null,
+    sent_at timestamp,
+    dead_at timestamp
+);
+
+create index email_outbox_due on email_outbox (next_attempt_at) where sent_at is null and dead_at is null;
+create index email_outbox_recipient on email_outbox (recipient, sent_at)
\ No newline at end of file
@@ -0,0 +1,12 @@
+-- Your SQL goes here
+-- Links sent before this migration were signed tokens, they stop working and can be sent again
+create table email_verification(
+    token_hash varchar primary key not null,
+    user_id uuid not null references users (id) on delete cascade on update cascade,
+    email varchar not null,
+    created_at timestamp not null,
+    expires_at timestamp not null,
+    used_at timestamp
+);
+
+create index email_verification_user on email_verification (user_id)
\ No newline at end of file
@@ -6,50 +6,327 @@ use uuid::Uuid;
 
 use crate::MyError;
 
+/// Sensitive operations need an authentication at most this old (seconds)
+pub const STEP_UP_MAX_AGE: i64 = 300;
+
+/// Sensitive operations need at least this `acr` level
+pub const STEP_UP_MIN_ACR: u32 = 1;
+
+/// How the user proved their identity when a session started
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuthMethod {
+    Password,
+    EmailLink,
+    /// Approved from another signed-in device, this device proved nothing itself
+    CrossDevice,
+}
+
+impl AuthMethod {
+    /// `amr` value (RFC 8176 where one fits)
+    pub fn amr(&self) -> &'static str {
+        match self {
+            AuthMethod::Password => "pwd",
+            AuthMethod::EmailLink => "otp",
+            AuthMethod::CrossDevice => "qr",
+        }
+    }
+
+    /// `acr` level: "1" when the user authenticated on this device, "0" otherwise
+    pub fn acr(&self) -> &'static str {
+        match self {
+            AuthMethod::Password | AuthMethod::EmailLink => "1",
+            AuthMethod::CrossDevice => "0",
+        }
+    }
+}
+
+/// When and how the user authenticated, carried from the refresh token to every access token
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct Authentication {
+    #[serde(default)]
+    pub auth_time: i64,
+    #[serde(default)]
+    pub amr: Vec<String>,
+    #[serde(default)]
+    pub acr: String,
+}
+
+impl Authentication {
+    /// An authentication that happened just now
+    pub fn new(method: AuthMethod) -> Self {
+        Authentication {
+            auth_time: Utc::now().timestamp(),
+            amr: vec![method.amr().to_string()],
+            acr: method.acr().to_string(),
+        }
+    }
+
+    /// Recent and strong enough for sensitive operations.
+    /// Levels are compared as numbers, a missing or malformed `acr` is the weakest.
+    pub fn is_step_up(&self) -> bool {
+        let level: u32 = self.acr.parse().unwrap_or(0);
+        Utc::now().timestamp() - self.auth_time <= STEP_UP_MAX_AGE && level >= STEP_UP_MIN_ACR
+    }
+}
+
+/// Scope of a session with full access
+pub const SCOPE_USER: &str = "user";
+
+/// Scope of a session whose email is not verified yet, sensitive operations are refused
+pub const SCOPE_UNVERIFIED: &str = "unverified";
+
+/// What happens to accounts whose email is not verified, from `EMAIL_VERIFICATION`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EmailVerificationPolicy {
+    /// Unverified accounts behave like verified ones
+    Off,
+    /// Unverified accounts get sessions with the `unverified` scope, which only
+    /// allow to verify the email, resend the verification and log out
+    Restrict,
+    /// Unverified accounts cannot log in
+    Block,
+}
+
+impl EmailVerificationPolicy {
+    pub fn from_env() -> Self {
+        match std::env::var("EMAIL_VERIFICATION")
+            .unwrap_or_default()
+            .trim()
+            .to_lowercase()
+            .as_str()
+        {
+            "restrict" => EmailVerificationPolicy::Restrict,
+            "block" => EmailVerificationPolicy::Block,
+            _ => EmailVerificationPolicy::Off,
+        }
+    }
+
+    /// Scope granted to a session of a user
+    pub fn scope(&self, email_verified: bool) -> &'static str {
+        match self {
+            EmailVerificationPolicy::Restrict if !email_verified => SCOPE_UNVERIFIED,
+            _ => SCOPE_USER,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
-    user_id: String,
+    pub user_id: String,
     exp: i64,
     iat: i64,
+    /// Unique per token, so two sessions started in the same second get distinct tokens
+    #[serde(default)]
+    jti: String,
+    #[serde(flatten)]
+    pub auth: Authentication,
+    #[serde(default)]
+    pub scope: String,
 }
 
-pub fn generate_access_token(user_id: Uuid) -> String {
+/// Claims of a signed, short-lived sign-in link
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LinkClaims {
+    pub email: String,
+    pub jti: String,
+    exp: i64,
+    iat: i64,
+}
+
+/// Claims of the long-lived `trusted_device` cookie
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeviceClaims {
+    pub user_id: String,
+    pub device_id: String,
+    exp: i64,
+    iat: i64,
+}
+
+/// Claims of a proof-of-work challenge for one route
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChallengeClaims {
+    pub route: String,
+    pub difficulty: u32,
+    pub jti: String,
+    pub exp: i64,
+    iat: i64,
+}
+
+pub fn generate_access_token(user_id: Uuid, auth: &Authentication, scope: &str) -> String {
     generate(
         user_id,
+        auth,
+        scope,
         chrono::Duration::seconds(30),
         "access_secret".to_string(),
     )
 }
 
-pub fn generate_refresh_token(user_id: Uuid) -> String {
+pub fn generate_refresh_token(user_id: Uuid, auth: &Authentication, scope: &str) -> String {
     generate(
         user_id,
+        auth,
+        scope,
         chrono::Duration::days(7),
         "refresh_secret".to_string(),
     )
 }
 
-fn generate(user_id: Uuid, duration: chrono::Duration, secret: String) -> String {
-    dbg!(duration.clone());
+fn generate(
+    user_id: Uuid,
+    auth: &Authentication,
+    scope: &str,
+    duration: chrono::Duration,
+    secret: String,
+) -> String {
+    dbg!(duration);
     let now = Utc::now();
-    dbg!(now.clone());
+    dbg!(now);
     let exp = Utc::now() + duration;
-    dbg!(exp.clone());
+    dbg!(exp);
 
     let claims = Claims {
         user_id: user_id.to_string(),
         iat: now.timestamp(),
         exp: exp.timestamp(),
+        jti: Uuid::new_v4().to_string(),
+        auth: auth.clone(),
+        scope: scope.to_string(),
+    };
+
+    jsonwebtoken::encode(
+        &jsonwebtoken::Header::default(),
+        &claims,
+        &jsonwebtoken::EncodingKey::from_secret(secret.as_bytes()),
+    )
+    .unwrap_or_default()
+}
+
+/// The HMAC key `name` from the environment. There is no default, a key written in this code could
+/// be used by anyone to forge tokens, so the server refuses to start without it.
+pub fn secret(name: &str) -> String {
+    std::env::var(name)
+        .ok()
+        .filter(|secret| !secret.trim().is_empty())
+        .unwrap_or_else(|| panic!("Missed '{name}' environment variable"))
+}
+
+/// Sign a sign-in link for `email` with `LOGIN_LINK_SECRET`, `jti` identifies the stored `LoginLink`
+pub fn generate_login_link_token(email: String, jti: String, duration: chrono::Duration) -> String {
+    let now = Utc::now();
+    let exp = now + duration;
+
+    let claims = LinkClaims {
+        email,
+        jti,
+        iat: now.timestamp(),
+        exp: exp.timestamp(),
+    };
+
+    jsonwebtoken::encode(
+        &jsonwebtoken::Header::default(),
+        &claims,
+        &jsonwebtoken::EncodingKey::from_secret(secret("LOGIN_LINK_SECRET").as_bytes()),
+    )
+    .unwrap_or_default()
+}
+
+/// Decode a sign-in link token, failing if the signature is wrong or it expired
+pub fn decode_login_link_token(token: String) -> Result<LinkClaims, MyError> {
+    match decode::<LinkClaims>(
+        &token,
+        &DecodingKey::from_secret(secret("LOGIN_LINK_SECRET").as_bytes()),
+        &Validation::new(Algorithm::HS256),
+    ) {
+        Ok(token_message) => Ok(token_message.claims),
+        Err(e) => Err(token_error(e)),
+    }
+}
+
+/// Sign the `trusted_device` cookie value for a remembered device with `DEVICE_SECRET`
+pub fn generate_device_token(user_id: Uuid, device_id: Uuid, duration: chrono::Duration) -> String {
+    let now = Utc::now();
+    let exp = now + duration;
+
+    let claims = DeviceClaims {
+        user_id: user_id.to_string(),
+        device_id: device_id.to_string(),
+        iat: now.timestamp(),
+        exp: exp.timestamp(),
+    };
+
+    jsonwebtoken::encode(
+        &jsonwebtoken::Header::default(),
+        &claims,
+        &jsonwebtoken::EncodingKey::from_secret(secret("DEVICE_SECRET").as_bytes()),
+    )
+    .unwrap_or_default()
+}
+
+/// Decode the `trusted_device` cookie value
+pub fn decode_device_token(token: String) -> Result<DeviceClaims, MyError> {
+    match decode::<DeviceClaims>(
+        &token,
+        &DecodingKey::from_secret(secret("DEVICE_SECRET").as_bytes()),
+        &Validation::new(Algorithm::HS256),
+    ) {
+        Ok(token_message) => Ok(token_message.claims),
+        Err(e) => Err(token_error(e)),
+    }
+}
+
+/// Sign a proof-of-work challenge with `secret`, solved by finding a hash with `difficulty`
+/// leading zero bits
+pub fn generate_challenge_token(
+    route: String,
+    difficulty: u32,
+    jti: String,
+    duration: chrono::Duration,
+    secret: &str,
+) -> String {
+    let now = Utc::now();
+    let exp = now + duration;
+
+    let claims = ChallengeClaims {
+        route,
+        difficulty,
+        jti,
+        iat: now.timestamp(),
+        exp: exp.timestamp(),
     };
 
     jsonwebtoken::encode(
         &jsonwebtoken::Header::default(),
         &claims,
-        &jsonwebtoken::EncodingKey::from_secret(&secret.as_bytes()),
+        &jsonwebtoken::EncodingKey::from_secret(secret.as_bytes()),
     )
     .unwrap_or_default()
 }
 
+/// Decode a proof-of-work challenge signed with `secret`
+pub fn decode_challenge_token(token: String, secret: &str) -> Result<ChallengeClaims, MyError> {
+    match decode::<ChallengeClaims>(
+        &token,
+        &DecodingKey::from_secret(secret.as_bytes()),
+        &Validation::new(Algorithm::HS256),
+    ) {
+        Ok(token_message) => Ok(token_message.claims),
+        Err(e) => Err(token_error(e)),
+    }
+}
+
+/// Tokens failing validation are unauthenticated, without details beyond expiry
+fn token_error(e: jsonwebtoken::errors::Error) -> MyError {
+    match e.kind() {
+        jsonwebtoken::errors::ErrorKind::ExpiredSignature => MyError::Unauthenticated {
+            desc: "Token expired".to_string(),
+        },
+        _ => MyError::Unauthenticated {
+            desc: "Invalid token".to_string(),
+        },
+    }
+}
+
 pub fn get_auth_from_header(request: HttpRequest) -> Result<String, MyError> {
     match request
         .headers()
@@ -58,53 +335,132 @@ pub fn get_auth_from_header(request: HttpRequest) -> Result<String, MyError> {
         Some(auth_header) => {
             let auth_values = auth_header
                 .to_str()
-                .unwrap()
+                .unwrap_or_default()
                 .split_whitespace()
                 .collect::<Vec<&str>>();
             match auth_values.first() {
                 Some(schema) => {
                     // ===
                     match schema.trim().to_lowercase().as_str() {
-                        "bearer" => Ok(auth_values[1].to_string()),
-                        _ => Err(MyError::General {
+                        "bearer" => match auth_values.get(1) {
+                            Some(token) => Ok(token.to_string()),
+                            None => Err(MyError::Unauthenticated {
+                                desc: "Empty bearer token".to_string(),
+                            }),
+                        },
+                        _ => Err(MyError::Unauthenticated {
                             desc: "Wrong Authentication scheme".to_string(),
                         }),
                     }
                 }
-                None => Err(MyError::General {
+                None => Err(MyError::Unauthenticated {
                     desc: "Empty `Authorization` schema".to_string(),
                 }),
             }
         }
         // No `Authorization` header found
-        None => Err(MyError::General {
+        None => Err(MyError::Unauthenticated {
             desc: "No `Authorization` header found".to_string(),
         }),
     }
 }
 
+/// Decode the claims of the access_token in the `Authorization` header of a request,
+/// refusing sessions with the `unverified` scope
+fn get_verified_claims(request: HttpRequest) -> Result<Claims, MyError> {
+    let auth_token = get_auth_from_header(request)?;
+    let claims = decode_access_claims(auth_token)?;
+
+    if claims.scope == SCOPE_UNVERIFIED {
+        return Err(MyError::EmailNotVerified);
+    }
+
+    Ok(claims)
+}
+
+/// Authenticate a request by the access_token in its `Authorization` header and return user_id
+pub fn get_user_id_from_header(request: HttpRequest) -> Result<Uuid, MyError> {
+    let claims = get_verified_claims(request)?;
+
+    Uuid::parse_str(&claims.user_id).map_err(|_| MyError::Unauthenticated {
+        desc: "Invalid token".to_string(),
+    })
+}
+
+/// Authenticate a request like `get_user_id_from_header`, additionally requiring
+/// a recent and strong enough authentication for sensitive operations
+pub fn require_step_up(request: HttpRequest) -> Result<Uuid, MyError> {
+    let claims = get_verified_claims(request)?;
+
+    if !claims.auth.is_step_up() {
+        return Err(MyError::StepUpRequired {
+            max_age: STEP_UP_MAX_AGE,
+            acr_values: STEP_UP_MIN_ACR.to_string(),
+        });
+    }
+
+    Uuid::parse_str(&claims.user_id).map_err(|_| MyError::Unauthenticated {
+        desc: "Invalid token".to_string(),
+    })
+}
+
 /// Decode access_token and return user_id
 pub fn decode_access_token(token: String) -> Result<String, MyError> {
-    decode_token(token, "access_secret".to_string())
+    decode_access_claims(token).map(|claims| claims.user_id)
 }
 
 /// Decode refresh_token and return user_id
 pub fn decode_refresh_token(token: String) -> Result<String, MyError> {
+    decode_refresh_claims(token).map(|claims| claims.user_id)
+}
+
+/// Decode access_token and return all its claims
+pub fn decode_access_claims(token: String) -> Result<Claims, MyError> {
+    decode_token(token, "access_secret".to_string())
+}
+
+/// Decode refresh_token and return all its claims
+pub fn decode_refresh_claims(token: String) -> Result<Claims, MyError> {
     decode_token(token, "refresh_secret".to_string())
 }
 
 /// Decode a token based on the a specified secret
-fn decode_token(token: String, secret: String) -> Result<String, MyError> {
+fn decode_token(token: String, secret: String) -> Result<Claims, MyError> {
     match decode::<Claims>(
         &token,
         &DecodingKey::from_secret(secret.as_ref()),
         &Validation::new(Algorithm::HS256),
     ) {
-        Ok(token_message) => Ok(token_message.claims.user_id),
-        Err(e) => {
-            return Err(MyError::General {
-                desc: format!("{}", e),
-            });
+        Ok(token_message) => Ok(token_message.claims),
+        Err(e) => Err(token_error(e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn authentication(acr: &str, age: i64) -> Authentication {
+        Authentication {
+            auth_time: Utc::now().timestamp() - age,
+            amr: vec!["pwd".to_string()],
+            acr: acr.to_string(),
         }
     }
+
+    #[test]
+    fn step_up_compares_acr_levels_as_numbers() {
+        assert!(authentication("1", 0).is_step_up());
+        assert!(authentication("2", 0).is_step_up());
+        assert!(authentication("10", 0).is_step_up());
+        assert!(!authentication("0", 0).is_step_up());
+        assert!(!authentication("", 0).is_step_up());
+        assert!(!authentication("high", 0).is_step_up());
+    }
+
+    #[test]
+    fn step_up_expires() {
+        assert!(authentication("1", STEP_UP_MAX_AGE).is_step_up());
+        assert!(!authentication("1", STEP_UP_MAX_AGE + 1).is_step_up());
+    }
 }
@@ -0,0 +1,37 @@
+use rust_training::{password::PasswordScheme, utils};
+use std::time::Duration;
+
+/// Print the iterations (Argon2id) or the cost (bcrypt) with which hashing a password takes at
+/// least the given milliseconds on this machine, default 250, for the scheme set in `.env`
+/// and never below its costs.
+///
+/// ```
+/// cargo run --release --bin calibrate_password_hash -- 250
+/// ```
+///
+/// Copy the printed variables into `.env` so that every restart and every host use the same
+/// parameters, rather than calibrating at startup with `PASSWORD_HASH_TARGET_MS`.
+fn main() {
+    utils::initiate_logging();
+
+    let target = std::env::args()
+        .nth(1)
+        .and_then(|ms| ms.parse().ok())
+        .unwrap_or(250);
+    match PasswordScheme::from_env().calibrate(Duration::from_millis(target)) {
+        PasswordScheme::Bcrypt { cost } => {
+            println!("PASSWORD_HASH=bcrypt");
+            println!("BCRYPT_COST={cost}");
+        }
+        PasswordScheme::Argon2id {
+            memory_kib,
+            iterations,
+            parallelism,
+        } => {
+            println!("PASSWORD_HASH=argon2id");
+            println!("ARGON2_MEMORY_KIB={memory_kib}");
+            println!("ARGON2_ITERATIONS={iterations}");
+            println!("ARGON2_PARALLELISM={parallelism}");
+        }
+    }
+}
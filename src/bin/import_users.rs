@@ -0,0 +1,38 @@
+use log::info;
+use rust_training::{
+    db::DbClientConn,
+    import::{import_users, ImportFormat},
+    utils,
+};
+
+/// Bulk import users with password hashes from another application.
+///
+/// ```
+/// cargo run --bin import_users -- <users.csv|users.jsonl> [csv|jsonl]
+/// ```
+///
+/// The report is printed as JSON, the exit code is 1 when some rows failed.
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    utils::initiate_logging();
+
+    let args: Vec<String> = std::env::args().collect();
+    let path = args.get(1).expect("Usage: import_users <file> [csv|jsonl]");
+    let format = match args.get(2) {
+        Some(format) => ImportFormat::from_path(&format!(".{format}")),
+        None => ImportFormat::from_path(path),
+    }
+    .expect("Unknown format, use a .csv or .jsonl file or pass csv or jsonl");
+
+    let data = std::fs::read(path)?;
+    let pool = DbClientConn::get_pool_connection();
+
+    info!("Importing {path} as {format:?}");
+    let report = import_users(format, &data, &pool).await;
+    println!("{}", serde_json::to_string_pretty(&report)?);
+
+    if report.failed > 0 {
+        std::process::exit(1);
+    }
+    Ok(())
+}
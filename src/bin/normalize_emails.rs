@@ -0,0 +1,28 @@
+use log::info;
+use rust_training::{db::DbClientConn, entity::user::User, utils};
+
+/// Fill `users.email_normalized` the way the application normalizes emails, after the
+/// `unique_email` migration which only backfills ASCII addresses.
+///
+/// ```
+/// cargo run --bin normalize_emails
+/// ```
+///
+/// Accounts whose emails are the same once normalized keep a null `email_normalized` and are
+/// printed in the JSON report, to merge by hand before running it again. The exit code is then 1.
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    utils::initiate_logging();
+
+    let pool = DbClientConn::get_pool_connection();
+    let report = User::normalize_emails(&pool)
+        .await
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+    info!("{} normalized emails updated", report.updated);
+    println!("{}", serde_json::to_string_pretty(&report)?);
+
+    if !report.collisions.is_empty() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
@@ -0,0 +1,321 @@
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::web::Data;
+use actix_web::{Error, ResponseError};
+use chrono::Duration;
+use futures::future::{ready, LocalBoxFuture, Ready};
+use log::warn;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::auth::{decode_challenge_token, generate_challenge_token};
+use crate::db::DbPool;
+use crate::entity::general::{ChallengeResponse, UsedChallenge};
+use crate::utils::random_token;
+use crate::MyError;
+
+/// Challenges must be solved within this time
+const CHALLENGE_TTL_SECONDS: i64 = 120;
+
+/// Self-hosted proof-of-work required on some routes, a CAPTCHA a script has to pay for in CPU.
+/// Read from `POW_ROUTES` (comma-separated, like `/register,/forgot`, none by default),
+/// `POW_DIFFICULTY` (leading zero bits, default 16), `POW_MAX_DIFFICULTY` (default 24) and
+/// `POW_RATE_THRESHOLD` (requests per minute to a route, default 30).
+/// Past the threshold each doubling of the rate adds a bit, doubling the work of new challenges.
+/// Challenges are signed with `CHALLENGE_SECRET`, a random secret of this process when unset.
+pub struct Challenges {
+    routes: Vec<String>,
+    secret: String,
+    difficulty: u32,
+    max_difficulty: u32,
+    rate_threshold: f64,
+    rates: Mutex<HashMap<String, RouteRate>>,
+    pool: DbPool,
+}
+
+/// Requests to a route in the current and the previous minute
+struct RouteRate {
+    window_start: Instant,
+    current: u64,
+    previous: u64,
+}
+
+impl RouteRate {
+    /// Requests over the last minute, counting the previous one in proportion to its overlap
+    fn per_minute(&mut self) -> f64 {
+        let elapsed = self.window_start.elapsed().as_secs_f64();
+        if elapsed >= 120.0 {
+            self.previous = 0;
+            self.current = 0;
+            self.window_start = Instant::now();
+        } else if elapsed >= 60.0 {
+            self.previous = self.current;
+            self.current = 0;
+            self.window_start += std::time::Duration::from_secs(60);
+        }
+
+        let overlap = 1.0 - self.window_start.elapsed().as_secs_f64() / 60.0;
+        self.current as f64 + self.previous as f64 * overlap.max(0.0)
+    }
+}
+
+impl Challenges {
+    pub fn from_env(pool: DbPool) -> Self {
+        let number = |name: &str, default: u32| {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        };
+        let difficulty = number("POW_DIFFICULTY", 16).min(64);
+        let secret = std::env::var("CHALLENGE_SECRET")
+            .ok()
+            .filter(|secret| !secret.trim().is_empty())
+            .unwrap_or_else(|| {
+                warn!("CHALLENGE_SECRET is not set, using a random secret of this process");
+                random_token(32)
+            });
+
+        Challenges {
+            routes: std::env::var("POW_ROUTES")
+                .unwrap_or_default()
+                .split(',')
+                .map(|route| route.trim().to_string())
+                .filter(|route| !route.is_empty())
+                .collect(),
+            secret,
+            difficulty,
+            max_difficulty: number("POW_MAX_DIFFICULTY", 24).clamp(difficulty, 64),
+            rate_threshold: number("POW_RATE_THRESHOLD", 30).max(1) as f64,
+            rates: Mutex::new(HashMap::new()),
+            pool,
+        }
+    }
+
+    pub fn is_protected(&self, route: &str) -> bool {
+        self.routes.iter().any(|protected| protected == route)
+    }
+
+    /// Issue a signed challenge for `route`, as hard as its recent traffic requires
+    pub fn issue(&self, route: &str) -> Result<ChallengeResponse, MyError> {
+        if !self.is_protected(route) {
+            return Err(MyError::Validation {
+                desc: format!("{route} does not require a challenge"),
+            });
+        }
+
+        let difficulty = self.difficulty_for(route);
+        let duration = Duration::seconds(CHALLENGE_TTL_SECONDS);
+        Ok(ChallengeResponse {
+            challenge: generate_challenge_token(
+                route.to_string(),
+                difficulty,
+                random_token(16),
+                duration,
+                &self.secret,
+            ),
+            algorithm: "sha256".to_string(),
+            difficulty,
+            expires_at: chrono::Utc::now().naive_utc() + duration,
+        })
+    }
+
+    /// Check the solution of a request to `route` and mark its challenge as used
+    pub async fn verify(
+        &self,
+        route: &str,
+        challenge: Option<&str>,
+        nonce: Option<&str>,
+    ) -> Result<(), MyError> {
+        let required = || MyError::ChallengeRequired {
+            desc: format!("Solve a challenge from GET /challenge?route={route}"),
+        };
+        let (challenge, nonce) = match (challenge, nonce) {
+            (Some(challenge), Some(nonce)) => (challenge, nonce),
+            _ => return Err(required()),
+        };
+
+        let claims =
+            decode_challenge_token(challenge.to_string(), &self.secret).map_err(|_| required())?;
+        // Easier challenges than the base difficulty were not issued by this server
+        if claims.route != route
+            || claims.difficulty < self.difficulty
+            || !is_solution(challenge, nonce, claims.difficulty)
+        {
+            return Err(required());
+        }
+
+        let expires_at = chrono::NaiveDateTime::from_timestamp(claims.exp, 0);
+        UsedChallenge::insert(
+            UsedChallenge {
+                jti: claims.jti,
+                expires_at,
+            },
+            &self.pool,
+        )
+        .await
+    }
+
+    /// Count a request to `route` towards its rate
+    fn record(&self, route: &str) {
+        if let Ok(mut rates) = self.rates.lock() {
+            let rate = rates.entry(route.to_string()).or_insert_with(|| RouteRate {
+                window_start: Instant::now(),
+                current: 0,
+                previous: 0,
+            });
+            rate.per_minute();
+            rate.current += 1;
+        }
+    }
+
+    fn difficulty_for(&self, route: &str) -> u32 {
+        let per_minute = match self.rates.lock() {
+            Ok(mut rates) => rates.get_mut(route).map_or(0.0, |rate| rate.per_minute()),
+            Err(_) => 0.0,
+        };
+
+        let extra = if per_minute > self.rate_threshold {
+            (per_minute / self.rate_threshold).log2().ceil() as u32
+        } else {
+            0
+        };
+        (self.difficulty + extra).min(self.max_difficulty)
+    }
+}
+
+/// Whether the SHA-256 of `<challenge>:<nonce>` starts with `difficulty` zero bits
+pub fn is_solution(challenge: &str, nonce: &str, difficulty: u32) -> bool {
+    let digest = Sha256::digest(format!("{challenge}:{nonce}").as_bytes());
+
+    let mut zeros = 0;
+    for byte in digest {
+        zeros += byte.leading_zeros();
+        if byte != 0 {
+            break;
+        }
+    }
+    zeros >= difficulty
+}
+
+/// Middleware requiring a solved challenge on the protected routes of a scope.
+/// The challenge goes in the `X-Challenge` header and its solution in `X-Challenge-Nonce`,
+/// requests without them are answered `428` with the `challenge_required` code.
+pub struct ProofOfWork {
+    challenges: Data<Challenges>,
+}
+
+impl ProofOfWork {
+    pub fn new(challenges: Data<Challenges>) -> Self {
+        ProofOfWork { challenges }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for ProofOfWork
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = ProofOfWorkMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ProofOfWorkMiddleware {
+            service: Rc::new(service),
+            challenges: self.challenges.clone(),
+        }))
+    }
+}
+
+pub struct ProofOfWorkMiddleware<S> {
+    service: Rc<S>,
+    challenges: Data<Challenges>,
+}
+
+impl<S, B> Service<ServiceRequest> for ProofOfWorkMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, request: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let challenges = self.challenges.clone();
+
+        Box::pin(async move {
+            let route = request.match_info().unprocessed().to_string();
+            if !challenges.is_protected(&route) {
+                return Ok(service.call(request).await?.map_into_left_body());
+            }
+            challenges.record(&route);
+
+            let header = |name: &str| {
+                request
+                    .headers()
+                    .get(name)
+                    .and_then(|value| value.to_str().ok())
+                    .map(str::to_string)
+            };
+            let challenge = header("x-challenge");
+            let nonce = header("x-challenge-nonce");
+            if let Err(e) = challenges
+                .verify(&route, challenge.as_deref(), nonce.as_deref())
+                .await
+            {
+                let response = e.error_response();
+                return Ok(request.into_response(response).map_into_right_body());
+            }
+
+            Ok(service.call(request).await?.map_into_left_body())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leading_zero_bits(challenge: &str, nonce: u64) -> u32 {
+        let digest = Sha256::digest(format!("{challenge}:{nonce}").as_bytes());
+        let bits: String = digest.iter().map(|byte| format!("{byte:08b}")).collect();
+        bits.chars().take_while(|bit| *bit == '0').count() as u32
+    }
+
+    #[test]
+    fn solutions_need_the_leading_zero_bits() {
+        let challenge = "challenge";
+        let nonce = (0..)
+            .find(|nonce| leading_zero_bits(challenge, *nonce) >= 10)
+            .unwrap();
+        let zeros = leading_zero_bits(challenge, nonce);
+
+        assert!(is_solution(challenge, &nonce.to_string(), 10));
+        assert!(is_solution(challenge, &nonce.to_string(), zeros));
+        assert!(!is_solution(challenge, &nonce.to_string(), zeros + 1));
+    }
+
+    #[test]
+    fn counts_bits_across_bytes() {
+        for nonce in 0..2000 {
+            for difficulty in [0, 1, 4, 7, 8, 9, 12] {
+                assert_eq!(
+                    is_solution("challenge", &nonce.to_string(), difficulty),
+                    leading_zero_bits("challenge", nonce) >= difficulty,
+                    "{nonce} {difficulty}"
+                );
+            }
+        }
+        assert!(!is_solution("challenge", "0", 257));
+    }
+}
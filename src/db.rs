@@ -1,26 +1,91 @@
+use actix_web::web;
 use diesel::{r2d2::ConnectionManager, PgConnection};
 // use diesel::{MysqlConnection};
 use dotenv::dotenv;
 use log::info;
 use r2d2::Pool;
 use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
 
-// pub type DbPool = Pool<ConnectionManager<MysqlConnection>>;
-pub type DbPool = Pool<ConnectionManager<PgConnection>>;
+use crate::MyError;
+
+// pub type ConnectionPool = Pool<ConnectionManager<MysqlConnection>>;
+pub type ConnectionPool = Pool<ConnectionManager<PgConnection>>;
 // The Postgres-specific connection pool managing all database connections.
 // pub type PostgresPool = Pool<ConnectionManager<PgConnection>>;
 
+/// Connections to the database, used off the async executor.
+/// At most `max_size` queries run at once, the others wait up to `timeout` for their turn.
+#[derive(Clone)]
+pub struct DbPool {
+    pool: ConnectionPool,
+    permits: Arc<Semaphore>,
+    timeout: Duration,
+}
+
+impl DbPool {
+    /// Run `query` with a connection on the blocking thread pool
+    pub async fn run<F, T>(&self, query: F) -> Result<T, MyError>
+    where
+        F: FnOnce(&PgConnection) -> Result<T, MyError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let permit = tokio::time::timeout(self.timeout, self.permits.clone().acquire_owned())
+            .await
+            .map_err(|_| MyError::ServiceUnavailable {
+                desc: "Timed out waiting for a database connection".to_string(),
+            })?
+            .map_err(|e| MyError::Internal {
+                desc: format!("{}", e),
+            })?;
+
+        let pool = self.pool.clone();
+        web::block(move || {
+            let _permit = permit;
+            let connection = pool.get()?;
+            query(&connection)
+        })
+        .await
+        .map_err(|e| MyError::Internal {
+            desc: format!("{}", e),
+        })?
+    }
+}
+
 pub struct DbClientConn;
 impl DbClientConn {
+    /// Build the pool from `DATABASE_URL`.
+    /// `DATABASE_POOL_SIZE` (default 10) and `DATABASE_POOL_TIMEOUT` in seconds (default 5) are optional.
     pub fn get_pool_connection() -> DbPool {
         // it from the environment within this function
         dotenv().ok();
         let url = env::var("DATABASE_URL").expect("Couldn't found 'DATABASE_URL' inside .env file");
         info!("DATABASE_URL: {url}");
+        let max_size: u32 = env::var("DATABASE_POOL_SIZE")
+            .ok()
+            .and_then(|size| size.parse().ok())
+            .unwrap_or(10);
+        let timeout = Duration::from_secs(
+            env::var("DATABASE_POOL_TIMEOUT")
+                .ok()
+                .and_then(|secs| secs.parse().ok())
+                .unwrap_or(5),
+        );
+        info!("DATABASE_POOL_SIZE: {max_size}, DATABASE_POOL_TIMEOUT: {timeout:?}");
         // TODO let migr = ConnectionManager::<MysqlConnection>::new(url);
         let migr = ConnectionManager::<PgConnection>::new(url);
-        r2d2::Pool::builder()
+        let pool = r2d2::Pool::builder()
+            .max_size(max_size)
+            .connection_timeout(timeout)
             .build(migr)
-            .expect("could not build connection pool")
+            .expect("could not build connection pool");
+
+        DbPool {
+            pool,
+            permits: Arc::new(Semaphore::new(max_size as usize)),
+            timeout,
+        }
     }
 }
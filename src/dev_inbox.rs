@@ -0,0 +1,193 @@
+use actix_web::web::ServiceConfig;
+use actix_web::{delete, get, web, HttpResponse};
+use tera::escape_html;
+
+use crate::entity::general::{DevEmailDTO, DevMailLink, DevMailQuery, MessageResponse};
+use crate::mailer::{MemoryMailer, SentEmail};
+use crate::MyError;
+
+/// The dev inbox, only mounted with `MAILER=memory`: the emails of the `MemoryMailer`
+/// in the browser at `/dev/mail` and as JSON under `/dev/mail/api` for end-to-end tests
+pub fn routes_config(config: &mut ServiceConfig) {
+    config
+        .service(list_messages)
+        .service(latest_link)
+        .service(get_message)
+        .service(clear_messages)
+        .service(inbox_page)
+        .service(message_page)
+        .service(message_html);
+}
+
+#[get("/dev/mail/api/messages")]
+/// Emails of the inbox, newest first, only those to `to` if given
+pub async fn list_messages(
+    query: web::Query<DevMailQuery>,
+    inbox: web::Data<MemoryMailer>,
+) -> Result<HttpResponse, MyError> {
+    let emails: Vec<DevEmailDTO> = sent(&inbox, query.to.as_deref()).iter().map(dto).collect();
+    Ok(HttpResponse::Ok().json(emails))
+}
+
+#[get("/dev/mail/api/latest")]
+/// The newest link sent to `to`, containing `contains` if given
+pub async fn latest_link(
+    query: web::Query<DevMailQuery>,
+    inbox: web::Data<MemoryMailer>,
+) -> Result<HttpResponse, MyError> {
+    let query = query.into_inner();
+    let to = query.to.ok_or(MyError::Validation {
+        desc: "`to` is required".to_string(),
+    })?;
+    let contains = query.contains.unwrap_or_default();
+
+    let link = inbox.sent_to(&to).into_iter().find_map(|sent| {
+        let link = sent
+            .links()
+            .into_iter()
+            .find(|link| link.contains(&contains))?;
+        Some(DevMailLink {
+            id: sent.id,
+            subject: sent.email.subject,
+            sent_at: sent.sent_at,
+            link,
+        })
+    });
+
+    match link {
+        Some(link) => Ok(HttpResponse::Ok().json(link)),
+        None => Err(MyError::NotFound {
+            desc: format!("No link sent to {to}"),
+        }),
+    }
+}
+
+#[get("/dev/mail/api/messages/{id}")]
+pub async fn get_message(
+    path: web::Path<u64>,
+    inbox: web::Data<MemoryMailer>,
+) -> Result<HttpResponse, MyError> {
+    let sent = find(&inbox, path.into_inner())?;
+    Ok(HttpResponse::Ok().json(dto(&sent)))
+}
+
+#[delete("/dev/mail/api/messages")]
+/// Empty the inbox, between end-to-end tests
+pub async fn clear_messages(inbox: web::Data<MemoryMailer>) -> Result<HttpResponse, MyError> {
+    let cleared = inbox.clear();
+
+    let response = MessageResponse {
+        message: format!("{cleared} emails deleted"),
+    };
+    Ok(HttpResponse::Ok().json(response))
+}
+
+#[get("/dev/mail")]
+pub async fn inbox_page(
+    query: web::Query<DevMailQuery>,
+    inbox: web::Data<MemoryMailer>,
+) -> Result<HttpResponse, MyError> {
+    let to = query.to.clone().unwrap_or_default();
+
+    let rows: String = sent(&inbox, query.to.as_deref())
+        .iter()
+        .map(|sent| {
+            format!(
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td><a href=\"/dev/mail/{}\">{}</a></td></tr>",
+                sent.id,
+                sent.sent_at.format("%Y-%m-%d %H:%M:%S"),
+                escape_html(&sent.email.to),
+                sent.id,
+                escape_html(&sent.email.subject)
+            )
+        })
+        .collect();
+
+    let content = format!(
+        "<form><input name=\"to\" placeholder=\"to\" value=\"{}\"> <button>Filter</button></form>\
+         <table><tr><th>#</th><th>Sent</th><th>To</th><th>Subject</th></tr>{rows}</table>",
+        escape_html(&to)
+    );
+    Ok(page("Dev mail", &content))
+}
+
+#[get("/dev/mail/{id}")]
+pub async fn message_page(
+    path: web::Path<u64>,
+    inbox: web::Data<MemoryMailer>,
+) -> Result<HttpResponse, MyError> {
+    let sent = find(&inbox, path.into_inner())?;
+
+    let links: String = sent
+        .links()
+        .iter()
+        .map(|link| {
+            let link = escape_html(link);
+            format!("<li><a href=\"{link}\">{link}</a></li>")
+        })
+        .collect();
+
+    let content = format!(
+        "<p><a href=\"/dev/mail\">Inbox</a></p>\
+         <p>To: {}<br>Sent: {}</p>\
+         <h2>Links</h2><ul>{links}</ul>\
+         <h2>HTML</h2><iframe sandbox src=\"/dev/mail/{}/html\"></iframe>\
+         <h2>Text</h2><pre>{}</pre>",
+        escape_html(&sent.email.to),
+        sent.sent_at.format("%Y-%m-%d %H:%M:%S"),
+        sent.id,
+        escape_html(&sent.email.text_body)
+    );
+    Ok(page(&sent.email.subject, &content))
+}
+
+#[get("/dev/mail/{id}/html")]
+/// The HTML body as is, sandboxed so its scripts never run
+pub async fn message_html(
+    path: web::Path<u64>,
+    inbox: web::Data<MemoryMailer>,
+) -> Result<HttpResponse, MyError> {
+    let sent = find(&inbox, path.into_inner())?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .insert_header(("Content-Security-Policy", "sandbox"))
+        .body(sent.email.html_body))
+}
+
+fn sent(inbox: &MemoryMailer, to: Option<&str>) -> Vec<SentEmail> {
+    match to.filter(|to| !to.trim().is_empty()) {
+        Some(to) => inbox.sent_to(to),
+        None => inbox.sent().into_iter().rev().collect(),
+    }
+}
+
+fn find(inbox: &MemoryMailer, id: u64) -> Result<SentEmail, MyError> {
+    inbox.find(id).ok_or(MyError::NotFound {
+        desc: "No email with this id".to_string(),
+    })
+}
+
+fn dto(sent: &SentEmail) -> DevEmailDTO {
+    DevEmailDTO {
+        id: sent.id,
+        to: sent.email.to.clone(),
+        subject: sent.email.subject.clone(),
+        sent_at: sent.sent_at,
+        links: sent.links(),
+        text_body: sent.email.text_body.clone(),
+        html_body: sent.email.html_body.clone(),
+    }
+}
+
+fn page(title: &str, content: &str) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(format!(
+            "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>{title}</title>\
+             <style>body{{font-family:sans-serif;margin:2em}}td,th{{padding:.3em .8em;text-align:left}}\
+             iframe{{width:100%;height:30em;border:1px solid #ccc}}pre{{white-space:pre-wrap}}</style>\
+             </head><body><h1>{title}</h1>{content}</body></html>",
+            title = escape_html(title)
+        ))
+}
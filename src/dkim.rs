@@ -0,0 +1,460 @@
+use log::info;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{Id, PKey, Private};
+use openssl::sign::Signer;
+use sha2::{Digest, Sha256};
+
+use crate::MyError;
+
+/// Headers signed when present, changing one of them breaks the signature
+const SIGNED_HEADERS: [&str; 7] = [
+    "from",
+    "to",
+    "subject",
+    "date",
+    "message-id",
+    "mime-version",
+    "content-type",
+];
+
+/// Signs outgoing messages with DKIM (RFC 6376), relaxed/relaxed canonicalization, so receivers
+/// can check they come from `DKIM_DOMAIN`.
+/// Read from `DKIM_PRIVATE_KEY_FILE`, a PEM RSA or Ed25519 private key, `DKIM_SELECTOR` and
+/// `DKIM_DOMAIN` (default the domain of `MAIL_FROM`). Receivers find the public key in the
+/// `<selector>._domainkey.<domain>` TXT record, logged at startup.
+pub struct DkimSigner {
+    domain: String,
+    selector: String,
+    key: PKey<Private>,
+}
+
+impl DkimSigner {
+    /// `None` when `DKIM_PRIVATE_KEY_FILE` is not set, messages are then sent unsigned
+    pub fn from_env(from: &str) -> Option<Self> {
+        let path = std::env::var("DKIM_PRIVATE_KEY_FILE").unwrap_or_default();
+        if path.trim().is_empty() {
+            return None;
+        }
+
+        let pem = std::fs::read(path.trim()).expect("could not read DKIM_PRIVATE_KEY_FILE");
+        let key = PKey::private_key_from_pem(&pem)
+            .expect("DKIM_PRIVATE_KEY_FILE is not a PEM private key");
+        let selector = std::env::var("DKIM_SELECTOR")
+            .ok()
+            .filter(|selector| !selector.trim().is_empty())
+            .expect("Missed 'DKIM_SELECTOR' environment variable");
+        let domain = std::env::var("DKIM_DOMAIN")
+            .ok()
+            .filter(|domain| !domain.trim().is_empty())
+            .unwrap_or_else(|| {
+                let address = from.trim().trim_end_matches('>');
+                address.rsplit('@').next().unwrap_or_default().to_string()
+            });
+
+        let signer = DkimSigner::new(domain, selector, key)
+            .unwrap_or_else(|e| panic!("DKIM_PRIVATE_KEY_FILE: {}", e));
+        info!(
+            "DKIM_DOMAIN: {}, DKIM_SELECTOR: {}, {}._domainkey.{} TXT \"{}\"",
+            signer.domain,
+            signer.selector,
+            signer.selector,
+            signer.domain,
+            signer.dns_record().unwrap_or_default()
+        );
+        Some(signer)
+    }
+
+    /// Fails unless `key` is an Ed25519 key or an RSA key of at least 1024 bits
+    pub fn new(domain: String, selector: String, key: PKey<Private>) -> Result<Self, MyError> {
+        match key.id() {
+            Id::ED25519 => {}
+            Id::RSA if key.bits() >= 1024 => {}
+            Id::RSA => {
+                return Err(MyError::Validation {
+                    desc: "DKIM RSA keys must have at least 1024 bits".to_string(),
+                })
+            }
+            _ => {
+                return Err(MyError::Validation {
+                    desc: "DKIM keys must be RSA or Ed25519".to_string(),
+                })
+            }
+        }
+
+        Ok(DkimSigner {
+            domain,
+            selector,
+            key,
+        })
+    }
+
+    /// Value of the TXT record publishing the public key
+    pub fn dns_record(&self) -> Result<String, MyError> {
+        let (kind, public_key) = match self.key.id() {
+            Id::ED25519 => ("ed25519", self.key.raw_public_key()),
+            _ => ("rsa", self.key.public_key_to_der()),
+        };
+        let public_key = public_key.map_err(|e| MyError::Internal {
+            desc: format!("{}", e),
+        })?;
+
+        Ok(format!(
+            "v=DKIM1; k={kind}; p={}",
+            base64::encode(public_key)
+        ))
+    }
+
+    /// `message` with CRLF line endings and a `DKIM-Signature` header first
+    pub fn sign(&self, message: &str) -> Result<String, MyError> {
+        // Signed as it will be sent, SMTP lines end with CRLF
+        let message = message.replace("\r\n", "\n").replace('\n', "\r\n");
+        let (header, body) = message
+            .split_once("\r\n\r\n")
+            .unwrap_or((message.trim_end_matches("\r\n"), ""));
+        let fields = header_fields(header);
+        let body_hash = base64::encode(Sha256::digest(canonical_body(body).as_bytes()));
+
+        // The last instance of a header is signed first, others are not signed
+        let mut data = String::new();
+        let mut signed = Vec::new();
+        for name in SIGNED_HEADERS {
+            let field = fields.iter().rev().find(|field| {
+                field
+                    .split_once(':')
+                    .is_some_and(|(field_name, _)| field_name.trim().eq_ignore_ascii_case(name))
+            });
+            if let Some(field) = field {
+                data.push_str(&canonical_header(field));
+                data.push_str("\r\n");
+                signed.push(name);
+            }
+        }
+
+        let algorithm = match self.key.id() {
+            Id::ED25519 => "ed25519-sha256",
+            _ => "rsa-sha256",
+        };
+        let value = format!(
+            "v=1; a={algorithm}; c=relaxed/relaxed; d={}; s={}; t={}; h={}; bh={body_hash}; b=",
+            self.domain,
+            self.selector,
+            chrono::Utc::now().timestamp(),
+            signed.join(":")
+        );
+        // The signature header itself is signed with an empty `b=` and without its CRLF
+        data.push_str(&canonical_header(&format!("DKIM-Signature: {value}")));
+        let signature = base64::encode(self.signature(data.as_bytes())?);
+
+        // Verifiers ignore the folding whitespace within `b=`
+        let folded: Vec<&str> = signature
+            .as_bytes()
+            .chunks(72)
+            .filter_map(|chunk| std::str::from_utf8(chunk).ok())
+            .collect();
+        Ok(format!(
+            "DKIM-Signature: {value}{}\r\n{message}",
+            folded.join("\r\n\t")
+        ))
+    }
+
+    fn signature(&self, data: &[u8]) -> Result<Vec<u8>, MyError> {
+        match self.key.id() {
+            // RFC 8463, the SHA-256 hash of the data is signed
+            Id::ED25519 => Signer::new_without_digest(&self.key)
+                .and_then(|mut signer| signer.sign_oneshot_to_vec(&Sha256::digest(data))),
+            _ => Signer::new(MessageDigest::sha256(), &self.key).and_then(|mut signer| {
+                signer.update(data)?;
+                signer.sign_to_vec()
+            }),
+        }
+        .map_err(|e| MyError::Internal {
+            desc: format!("{}", e),
+        })
+    }
+}
+
+/// Header fields of a header block, continuation lines joined to their field
+fn header_fields(header: &str) -> Vec<String> {
+    let mut fields: Vec<String> = Vec::new();
+    for line in header.split("\r\n") {
+        match fields.last_mut() {
+            Some(field) if line.starts_with([' ', '\t']) => {
+                field.push_str("\r\n");
+                field.push_str(line);
+            }
+            _ => fields.push(line.to_string()),
+        }
+    }
+    fields
+}
+
+/// Relaxed header canonicalization: lowercase name, unfolded value with single spaces, no
+/// whitespace around the colon
+fn canonical_header(field: &str) -> String {
+    let (name, value) = field.split_once(':').unwrap_or((field, ""));
+    let value = compress_whitespace(&value.replace("\r\n", ""));
+
+    format!("{}:{}", name.trim().to_lowercase(), value.trim_matches(' '))
+}
+
+/// Relaxed body canonicalization: single spaces, no trailing whitespace nor trailing empty lines
+fn canonical_body(body: &str) -> String {
+    let mut lines: Vec<String> = body
+        .split("\r\n")
+        .map(|line| compress_whitespace(line).trim_end_matches(' ').to_string())
+        .collect();
+    while lines.last().is_some_and(|line| line.is_empty()) {
+        lines.pop();
+    }
+
+    lines
+        .iter()
+        .map(|line| format!("{line}\r\n"))
+        .collect::<String>()
+}
+
+/// Every run of spaces and tabs replaced by a single space
+fn compress_whitespace(value: &str) -> String {
+    let mut compressed = String::with_capacity(value.len());
+    let mut previous_whitespace = false;
+    for character in value.chars() {
+        let whitespace = character == ' ' || character == '\t';
+        if !(whitespace && previous_whitespace) {
+            compressed.push(if whitespace { ' ' } else { character });
+        }
+        previous_whitespace = whitespace;
+    }
+    compressed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::rsa::Rsa;
+    use openssl::sign::Verifier;
+    use std::collections::HashMap;
+
+    const MESSAGE: &str = "From: Site <no-reply@site.com>\n\
+        To: jane@example.com\n\
+        Subject: Verify your\n \temail  address\n\
+        Date: Sun, 24 Apr 2022 09:00:00 +0000\n\
+        MIME-Version: 1.0\n\
+        Content-Type: text/plain; charset=utf-8\n\
+        X-Not-Signed: value\n\
+        \n\
+        Hello  Jane, \t\n\
+        \n\
+        \tOpen this link:\n\
+        https://site.com/verify/abc\n\
+        \n\
+        \n";
+
+    /// Checks a signed message the way a receiver does, apart from the signer: the tags are read
+    /// from the header as sent, both canonicalizations are redone and the signature is checked
+    /// with the public key of the DNS record
+    fn verify(signed: &str, record: &str) -> Result<(), String> {
+        let (header, body) = signed.split_once("\r\n\r\n").ok_or("no body")?;
+        let mut fields: Vec<String> = vec![];
+        for line in header.split("\r\n") {
+            if line.starts_with(' ') || line.starts_with('\t') {
+                let field = fields.last_mut().ok_or("continuation first")?;
+                *field = format!("{field}\r\n{line}");
+            } else {
+                fields.push(line.to_string());
+            }
+        }
+
+        let signature_field = fields.remove(0);
+        let (name, value) = signature_field.split_once(':').ok_or("no colon")?;
+        if name != "DKIM-Signature" {
+            return Err(format!("first header is {name}"));
+        }
+        let tags: HashMap<&str, String> = value
+            .split(';')
+            .filter_map(|tag| tag.split_once('='))
+            .map(|(name, value)| (name.trim(), value.split_whitespace().collect::<String>()))
+            .collect();
+        let tag = |name: &str| tags.get(name).cloned().ok_or(format!("no {name}="));
+
+        if tag("v")? != "1" || tag("c")? != "relaxed/relaxed" {
+            return Err("unexpected v= or c=".to_string());
+        }
+        let body_hash = base64::encode(Sha256::digest(relaxed_body(body).as_bytes()));
+        if tag("bh")? != body_hash {
+            return Err("body hash mismatch".to_string());
+        }
+
+        // Instances of a name are signed from the last one up
+        let mut data = String::new();
+        let mut remaining = fields.clone();
+        for name in tag("h")?.split(':') {
+            let position = remaining.iter().rposition(|field| {
+                field
+                    .split(':')
+                    .next()
+                    .is_some_and(|field_name| field_name.trim().eq_ignore_ascii_case(name))
+            });
+            if let Some(position) = position {
+                data += &relaxed_header(&remaining.remove(position));
+                data += "\r\n";
+            }
+        }
+        let without_b: Vec<&str> = value
+            .split(';')
+            .map(|tag| match tag.find("b=") {
+                Some(start) if tag.trim_start().starts_with("b=") => &tag[..start + 2],
+                _ => tag,
+            })
+            .collect();
+        data += &relaxed_header(&format!("{name}:{}", without_b.join(";")));
+
+        let record: HashMap<&str, &str> = record
+            .split(';')
+            .filter_map(|tag| tag.split_once('='))
+            .map(|(name, value)| (name.trim(), value.trim()))
+            .collect();
+        let public_key = base64::decode(record["p"]).map_err(|e| e.to_string())?;
+        let signature = base64::decode(tag("b")?).map_err(|e| e.to_string())?;
+        let valid = match (tag("a")?.as_str(), record["k"]) {
+            ("rsa-sha256", "rsa") => {
+                let key = PKey::public_key_from_der(&public_key).map_err(|e| e.to_string())?;
+                let mut verifier =
+                    Verifier::new(MessageDigest::sha256(), &key).map_err(|e| e.to_string())?;
+                verifier
+                    .update(data.as_bytes())
+                    .map_err(|e| e.to_string())?;
+                verifier.verify(&signature)
+            }
+            ("ed25519-sha256", "ed25519") => {
+                let key = PKey::public_key_from_raw_bytes(&public_key, Id::ED25519)
+                    .map_err(|e| e.to_string())?;
+                Verifier::new_without_digest(&key).and_then(|mut verifier| {
+                    verifier.verify_oneshot(&signature, &Sha256::digest(data.as_bytes()))
+                })
+            }
+            (algorithm, kind) => return Err(format!("a={algorithm} with k={kind}")),
+        }
+        .map_err(|e| e.to_string())?;
+
+        if valid {
+            Ok(())
+        } else {
+            Err("bad signature".to_string())
+        }
+    }
+
+    /// RFC 6376 3.4.2, written apart from `canonical_header`
+    fn relaxed_header(field: &str) -> String {
+        let (name, value) = field.split_once(':').unwrap();
+        let value: Vec<&str> = value
+            .split(['\r', '\n', ' ', '\t'])
+            .filter(|word| !word.is_empty())
+            .collect();
+        format!("{}:{}", name.trim().to_ascii_lowercase(), value.join(" "))
+    }
+
+    /// RFC 6376 3.4.4, written apart from `canonical_body`
+    fn relaxed_body(body: &str) -> String {
+        let mut canonical = String::new();
+        for line in body.split("\r\n") {
+            let mut line = line.replace('\t', " ");
+            while line.contains("  ") {
+                line = line.replace("  ", " ");
+            }
+            canonical += line.trim_end_matches(' ');
+            canonical += "\r\n";
+        }
+        while canonical.ends_with("\r\n\r\n") {
+            canonical.truncate(canonical.len() - 2);
+        }
+        if canonical == "\r\n" {
+            canonical.clear();
+        }
+        canonical
+    }
+
+    fn signer(key: PKey<Private>) -> DkimSigner {
+        DkimSigner::new("site.com".to_string(), "mail".to_string(), key).unwrap()
+    }
+
+    #[test]
+    fn relaxed_header_canonicalization_follows_rfc_6376() {
+        // RFC 6376 3.4.5
+        let canonical: String = header_fields("A: X\r\nB : Y\t\r\n\tZ  ")
+            .iter()
+            .map(|field| format!("{}\r\n", canonical_header(field)))
+            .collect();
+
+        assert_eq!(canonical, "a:X\r\nb:Y Z\r\n");
+    }
+
+    #[test]
+    fn relaxed_body_canonicalization_follows_rfc_6376() {
+        // RFC 6376 3.4.5
+        assert_eq!(canonical_body(" C \r\nD \t E\r\n\r\n\r\n"), " C\r\nD E\r\n");
+        assert_eq!(canonical_body(""), "");
+        assert_eq!(canonical_body("\r\n\r\n"), "");
+        assert_eq!(
+            canonical_body("no final line break"),
+            "no final line break\r\n"
+        );
+    }
+
+    #[test]
+    fn rsa_signatures_verify() {
+        let signer = signer(PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap());
+        let signed = signer.sign(MESSAGE).unwrap();
+
+        assert!(signed.starts_with(
+            "DKIM-Signature: v=1; a=rsa-sha256; c=relaxed/relaxed; d=site.com; s=mail;"
+        ));
+        assert!(signed.contains("; h=from:to:subject:date:mime-version:content-type; bh="));
+        verify(&signed, &signer.dns_record().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn ed25519_signatures_verify() {
+        let signer = signer(PKey::generate_ed25519().unwrap());
+        let signed = signer.sign(MESSAGE).unwrap();
+
+        assert!(signed.contains("a=ed25519-sha256"));
+        verify(&signed, &signer.dns_record().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn signatures_survive_relaxed_changes_only() {
+        let signer = signer(PKey::generate_ed25519().unwrap());
+        let record = signer.dns_record().unwrap();
+        let signed = signer.sign(MESSAGE).unwrap();
+
+        // Rewrapped headers, changed whitespace and unsigned headers are fine
+        let relaxed = signed
+            .replace(
+                "Subject: Verify your\r\n \temail  address",
+                "subject:Verify   your email address",
+            )
+            .replace("Hello  Jane, \t", "Hello Jane,")
+            .replace("X-Not-Signed: value", "X-Not-Signed: other");
+        verify(&relaxed, &record).unwrap();
+
+        let tampered_body = signed.replace("verify/abc", "verify/abd");
+        assert_eq!(
+            verify(&tampered_body, &record),
+            Err("body hash mismatch".to_string())
+        );
+        let tampered_header = signed.replace("To: jane@example.com", "To: john@example.com");
+        assert_eq!(
+            verify(&tampered_header, &record),
+            Err("bad signature".to_string())
+        );
+
+        let other = self::signer(PKey::generate_ed25519().unwrap());
+        assert!(verify(&signed, &other.dns_record().unwrap()).is_err());
+    }
+
+    #[test]
+    fn weak_keys_are_refused() {
+        let key = PKey::from_rsa(Rsa::generate(512).unwrap()).unwrap();
+        assert!(DkimSigner::new("site.com".to_string(), "mail".to_string(), key).is_err());
+    }
+}
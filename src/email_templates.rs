@@ -0,0 +1,237 @@
+use actix_web::HttpRequest;
+use include_dir::{include_dir, Dir};
+use log::info;
+use serde::Serialize;
+use std::path::Path;
+use tera::{Context, Tera};
+
+use crate::mailer::Email;
+use crate::MyError;
+
+/// Templates shipped with the application, `templates/email/<locale>/<name>.<part>`
+static BUILT_IN: Dir = include_dir!("$CARGO_MANIFEST_DIR/templates/email");
+
+/// Templates of the transactional emails, in every available locale.
+/// Each email `<name>` has three parts per locale: `<locale>/<name>.subject.txt`,
+/// `<locale>/<name>.html` and `<locale>/<name>.txt`, HTML parts escape every variable.
+/// Files of the `EMAIL_TEMPLATES_DIR` directory, laid out the same way, replace or add templates
+/// and locales at startup. Parts missing in a locale fall back to `DEFAULT_LOCALE` (default `en`).
+pub struct EmailTemplates {
+    tera: Tera,
+    locales: Vec<String>,
+    default_locale: String,
+}
+
+impl EmailTemplates {
+    pub fn from_env() -> Self {
+        let mut templates = Vec::new();
+        built_in(&BUILT_IN, &mut templates);
+
+        let dir = std::env::var("EMAIL_TEMPLATES_DIR").unwrap_or_default();
+        if !dir.trim().is_empty() {
+            info!("EMAIL_TEMPLATES_DIR: {dir}");
+            overrides(Path::new(&dir), "", &mut templates)
+                .expect("could not read EMAIL_TEMPLATES_DIR");
+        }
+
+        let mut tera = Tera::default();
+        tera.autoescape_on(vec![".html"]);
+        tera.add_raw_templates(templates)
+            .unwrap_or_else(|e| panic!("invalid email template: {}", describe(&e)));
+
+        let mut locales: Vec<String> = tera
+            .get_template_names()
+            .filter_map(|name| name.split_once('/').map(|(locale, _)| locale.to_string()))
+            .collect();
+        locales.sort();
+        locales.dedup();
+
+        let default_locale = std::env::var("DEFAULT_LOCALE").unwrap_or_else(|_| "en".to_string());
+        info!(
+            "email locales: {:?}, DEFAULT_LOCALE: {default_locale}",
+            locales
+        );
+
+        EmailTemplates {
+            tera,
+            locales,
+            default_locale,
+        }
+    }
+
+    /// Available locale best matching the language tag `tag`, `pt-BR` matches `pt-br` then `pt`
+    pub fn negotiate(&self, tag: &str) -> Option<&str> {
+        let tag = tag.trim().to_lowercase().replace('_', "-");
+        let primary = tag.split('-').next().unwrap_or_default();
+
+        let matching = |candidate: &str| {
+            self.locales
+                .iter()
+                .position(|locale| locale.to_lowercase() == candidate)
+        };
+        let index = matching(&tag).or_else(|| matching(primary))?;
+        Some(self.locales[index].as_str())
+    }
+
+    /// Locale of an email: the preference of the user if available, then the languages of
+    /// `accept_language` by decreasing quality, then `DEFAULT_LOCALE`
+    pub fn locale_for(&self, preferred: Option<&str>, accept_language: Option<&str>) -> String {
+        preferred
+            .and_then(|tag| self.negotiate(tag))
+            .or_else(|| {
+                parse_accept_language(accept_language.unwrap_or_default())
+                    .into_iter()
+                    .find_map(|tag| self.negotiate(&tag))
+            })
+            .unwrap_or(&self.default_locale)
+            .to_string()
+    }
+
+    /// Render the email `name` to `to` in `locale`, `variables` must serialize to a map
+    pub fn render(
+        &self,
+        name: &str,
+        locale: &str,
+        to: &str,
+        variables: impl Serialize,
+    ) -> Result<Email, MyError> {
+        let mut context = Context::from_serialize(variables)
+            .map_err(|e| MyError::Internal { desc: describe(&e) })?;
+        context.insert("locale", locale);
+
+        let part = |extension: &str| {
+            let mut template = format!("{locale}/{name}.{extension}");
+            if !self.tera.get_template_names().any(|name| name == template) {
+                template = format!("{}/{name}.{extension}", self.default_locale);
+            }
+
+            self.tera
+                .render(&template, &context)
+                .map_err(|e| MyError::Internal { desc: describe(&e) })
+        };
+
+        // A subject is a single header line whatever the variables contain
+        let subject = part("subject.txt")?
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        Ok(Email::new(to, subject, part("html")?, part("txt")?))
+    }
+}
+
+/// Value of the `Accept-Language` header of `request`
+pub fn accept_language(request: &HttpRequest) -> Option<&str> {
+    request
+        .headers()
+        .get(actix_web::http::header::ACCEPT_LANGUAGE)
+        .and_then(|value| value.to_str().ok())
+}
+
+/// Language tags of an `Accept-Language` header by decreasing quality, without `*`
+fn parse_accept_language(header: &str) -> Vec<String> {
+    let mut tags: Vec<(String, f32)> = header
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let tag = parts.next()?.trim();
+            let quality = parts
+                .find_map(|parameter| parameter.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |quality| quality.parse().ok())?;
+
+            (!tag.is_empty() && tag != "*" && quality > 0.0).then(|| (tag.to_string(), quality))
+        })
+        .collect();
+
+    // stable, so equal qualities keep the order of the header
+    tags.sort_by(|a, b| b.1.total_cmp(&a.1));
+    tags.into_iter().map(|(tag, _)| tag).collect()
+}
+
+fn built_in(dir: &Dir, templates: &mut Vec<(String, String)>) {
+    for file in dir.files() {
+        if let Some(content) = file.contents_utf8() {
+            templates.push((template_name(file.path()), content.to_string()));
+        }
+    }
+    for dir in dir.dirs() {
+        built_in(dir, templates);
+    }
+}
+
+/// Read the templates under `dir`, replacing the built-in ones of the same name
+fn overrides(
+    dir: &Path,
+    prefix: &str,
+    templates: &mut Vec<(String, String)>,
+) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = format!(
+            "{prefix}{}",
+            path.file_name().unwrap_or_default().to_string_lossy()
+        );
+
+        if path.is_dir() {
+            overrides(&path, &format!("{name}/"), templates)?;
+        } else {
+            let content = std::fs::read_to_string(&path)?;
+            templates.retain(|(existing, _)| *existing != name);
+            templates.push((name, content));
+        }
+    }
+
+    Ok(())
+}
+
+fn template_name(path: &Path) -> String {
+    path.components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Tera errors only tell what failed in their message, the reason is in their sources
+fn describe(e: &tera::Error) -> String {
+    let mut description = e.to_string();
+    let mut source = std::error::Error::source(e);
+    while let Some(cause) = source {
+        description.push_str(&format!(": {cause}"));
+        source = cause.source();
+    }
+    description
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orders_languages_by_quality() {
+        assert_eq!(
+            parse_accept_language("fr-CH, fr;q=0.9, en;q=0.8, de;q=0.7, *;q=0.5"),
+            ["fr-CH", "fr", "en", "de"]
+        );
+        assert_eq!(
+            parse_accept_language("en;q=0.5, pt-BR, de;q=0.8"),
+            ["pt-BR", "de", "en"]
+        );
+    }
+
+    #[test]
+    fn keeps_the_header_order_of_equal_qualities() {
+        assert_eq!(
+            parse_accept_language("de, en;q=0.7, fr, it;q=0.7"),
+            ["de", "fr", "en", "it"]
+        );
+    }
+
+    #[test]
+    fn skips_refused_and_malformed_languages() {
+        assert_eq!(
+            parse_accept_language("en;q=0, fr;q=abc, *, de ; q=0.3, ,"),
+            ["de"]
+        );
+        assert!(parse_accept_language("").is_empty());
+    }
+}
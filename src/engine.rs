@@ -1,97 +1,515 @@
 use crate::{
     db::DbPool,
-    entity::user::{Reset, User, UserDTO, UserRegisterationRequest, UserToken},
-    schema::{reset::dsl::reset as reset_schema, user_token::dsl::user_token, users::dsl::users},
+    entity::general::{FieldError, OutboxEmail, RateLimitBucket, UsedChallenge},
+    entity::user::{
+        CollidingAccount, EmailCollision, EmailVerification, LoginFailure, LoginLink,
+        NormalizeEmailsReport, PasswordHistory, PendingLogin, Reset, TrustedDevice, User, UserDTO,
+        UserImportRow, UserRegisterationRequest, UserToken,
+    },
+    hasher::Hasher,
+    lockout::{Block, LockoutPolicy, Thresholds},
+    mailer::Email,
+    password::PasswordScheme,
+    password_policy::PasswordPolicy,
+    schema::{
+        email_outbox::dsl::email_outbox, email_verification::dsl::email_verification,
+        login_failure::dsl::login_failure, login_link::dsl::login_link,
+        password_history::dsl::password_history, pending_login::dsl::pending_login,
+        reset::dsl::reset as reset_schema, trusted_device::dsl::trusted_device,
+        user_token::dsl::user_token, users::dsl::users,
+    },
+    utils::normalize_email,
     MyError,
 };
 
-use bcrypt::verify;
-use bcrypt::{hash, DEFAULT_COST};
 use diesel::prelude::*;
+use log::warn;
+use std::collections::HashMap;
 use uuid::Uuid;
 
 impl User {
+    /// Insert a new user, storing the email verification of the user and queuing its email
+    /// in the same transaction
     pub async fn insert(
         incoming: UserRegisterationRequest,
+        verification: impl FnOnce(&UserDTO) -> Result<(EmailVerification, Email), MyError>
+            + Send
+            + 'static,
+        hasher: &Hasher,
         pool: &DbPool,
     ) -> Result<UserDTO, MyError> {
-        let hashed_password = hash(&incoming.password, DEFAULT_COST).unwrap();
+        let hashed_password = hasher.hash(incoming.password).await?;
         let new_user = User {
             id: Uuid::new_v4(),
             first_name: incoming.first_name,
             last_name: incoming.last_name,
-            email: incoming.email,
+            email_normalized: Some(normalize_email(&incoming.email)),
+            email: incoming.email.trim().to_string(),
             password: hashed_password,
+            email_verified_at: None,
+            locale: incoming.locale,
         };
-        let connection = pool.get().unwrap();
-        let feedback: User = diesel::insert_into(crate::schema::users::dsl::users)
-            .values(&new_user)
-            .get_result(&connection)
-            .unwrap();
+        pool.run(move |connection| {
+            connection.transaction::<_, MyError, _>(|| {
+                let feedback: User = diesel::insert_into(crate::schema::users::dsl::users)
+                    .values(&new_user)
+                    .get_result(connection)
+                    .map_err(|e| match MyError::from(e) {
+                        MyError::Conflict { .. } => MyError::Conflict {
+                            desc: "Email already registered".to_string(),
+                        },
+                        e => e,
+                    })?;
 
-        Ok(feedback.as_dto())
+                let user = feedback.as_dto();
+                let (verification, verification_email) = verification(&user)?;
+                diesel::insert_into(email_verification)
+                    .values(&verification)
+                    .execute(connection)?;
+                OutboxEmail::enqueue(verification_email, connection)?;
+                Ok(user)
+            })
+        })
+        .await
+    }
+
+    /// Insert a user migrated from another application, keeping its password hash as is.
+    /// The hash is replaced with the current scheme on the first successful login.
+    pub async fn import(incoming: UserImportRow, pool: &DbPool) -> Result<UserDTO, MyError> {
+        let email = incoming.email.trim().to_string();
+        if !email.contains('@') {
+            return Err(MyError::Validation {
+                desc: "Invalid email".to_string(),
+            });
+        }
+        if !PasswordScheme::is_supported(&incoming.password_hash) {
+            return Err(MyError::Validation {
+                desc: "Unsupported password hash format".to_string(),
+            });
+        }
+
+        let new_user = User {
+            id: Uuid::new_v4(),
+            first_name: incoming.first_name,
+            last_name: incoming.last_name,
+            email_normalized: Some(normalize_email(&email)),
+            email,
+            password: incoming.password_hash,
+            email_verified_at: incoming
+                .email_verified
+                .then(|| chrono::Utc::now().naive_utc()),
+            locale: None,
+        };
+        pool.run(move |connection| {
+            let feedback: User = diesel::insert_into(crate::schema::users::dsl::users)
+                .values(&new_user)
+                .get_result(connection)
+                .map_err(|e| match MyError::from(e) {
+                    MyError::Conflict { .. } => MyError::Conflict {
+                        desc: "Email already registered".to_string(),
+                    },
+                    e => e,
+                })?;
+
+            Ok(feedback.as_dto())
+        })
+        .await
     }
 
     pub async fn find_by_id(incoming_id: Uuid, pool: &DbPool) -> Result<UserDTO, MyError> {
-        let connection = pool.get().unwrap();
-        let feedback: User = users.find(incoming_id).first(&connection).unwrap();
+        pool.run(move |connection| {
+            let feedback: User = users
+                .find(incoming_id)
+                .first(connection)
+                .optional()?
+                .ok_or(MyError::NotFound {
+                    desc: "User not found".to_string(),
+                })?;
 
-        Ok(feedback.as_dto())
+            Ok(feedback.as_dto())
+        })
+        .await
     }
 
+    /// Find a user by `normalize_email` of the email, or by the exact email while
+    /// `email_normalized` is not filled yet
     pub async fn find_by_email(incoming_email: String, pool: &DbPool) -> Result<User, MyError> {
-        use crate::schema::users::email;
+        use crate::schema::users::{email, email_normalized};
 
-        let connection = pool.get().unwrap();
-        let feedback: User = users
-            .filter(email.eq(incoming_email))
-            .first(&connection)
-            .unwrap();
+        pool.run(move |connection| {
+            let feedback: User = users
+                .filter(
+                    email_normalized
+                        .eq(normalize_email(&incoming_email))
+                        .or(email_normalized
+                            .is_null()
+                            .and(email.eq(incoming_email.trim()))),
+                )
+                .first(connection)
+                .optional()?
+                .ok_or(MyError::NotFound {
+                    desc: "User not found".to_string(),
+                })?;
 
-        Ok(feedback)
+            Ok(feedback)
+        })
+        .await
     }
 
     pub async fn get_all(pool: &DbPool) -> Result<Vec<User>, MyError> {
-        let connection = pool.get().unwrap();
-        let users_list = users.load::<User>(&connection).unwrap();
+        pool.run(move |connection| {
+            let users_list = users.load::<User>(connection)?;
 
-        Ok(users_list)
+            Ok(users_list)
+        })
+        .await
     }
 
     pub async fn authenticate_by_email(
         email: String,
         incoming_password: String,
+        hasher: &Hasher,
         pool: &DbPool,
     ) -> Result<User, MyError> {
-        let user = User::find_by_email(email, &pool).await.unwrap();
+        let invalid = || MyError::Unauthenticated {
+            desc: "Invalid email or password".to_string(),
+        };
 
-        if verify(incoming_password, &user.password).unwrap() {
-            Ok(user)
-        } else {
-            Err(MyError::General {
-                desc: "unauthenticated".to_string(),
-            })
+        let mut user = match User::find_by_email(email, pool).await {
+            Ok(user) => user,
+            Err(MyError::NotFound { .. }) => {
+                hasher.verify_dummy(incoming_password).await?;
+                return Err(invalid());
+            }
+            Err(e) => return Err(e),
+        };
+
+        if !hasher
+            .verify(incoming_password.clone(), user.password.clone())
+            .await?
+        {
+            return Err(invalid());
+        }
+
+        // Upgrade a hash made with an outdated algorithm or cost while the password is at hand.
+        // The user is signed in even if that fails, the next login will try again.
+        if hasher.needs_rehash(&user.password) {
+            match User::rehash_password(user.id, incoming_password, hasher, pool).await {
+                Ok(hashed_password) => user.password = hashed_password,
+                Err(e) => warn!("rehash_password {}: {}", user.id, e),
+            }
+        }
+
+        Ok(user)
+    }
+
+    /// Store a new hash of the current password, unlike `update_password` sessions and devices are kept
+    async fn rehash_password(
+        incoming_id: Uuid,
+        incoming_password: String,
+        hasher: &Hasher,
+        pool: &DbPool,
+    ) -> Result<String, MyError> {
+        use crate::schema::users::dsl::password;
+        let hashed_password = hasher.hash(incoming_password).await?;
+
+        pool.run(move |connection| {
+            diesel::update(users.find(incoming_id))
+                .set(password.eq(&hashed_password))
+                .execute(connection)?;
+
+            Ok(hashed_password)
+        })
+        .await
+    }
+
+    /// Mark `incoming_email` as verified, as long as it is still the email of the user
+    pub async fn verify_email(
+        incoming_id: Uuid,
+        incoming_email: String,
+        pool: &DbPool,
+    ) -> Result<(), MyError> {
+        pool.run(move |connection| {
+            User::mark_verified(incoming_id, normalize_email(&incoming_email), connection)
+        })
+        .await
+    }
+
+    /// Mark the email of a user as verified, failing if the user no longer has `incoming_email`
+    fn mark_verified(
+        incoming_id: Uuid,
+        incoming_email: String,
+        connection: &PgConnection,
+    ) -> Result<(), MyError> {
+        use crate::schema::users::dsl::{email_verified_at, id};
+
+        let user: User = users
+            .filter(id.eq(incoming_id))
+            .first(connection)
+            .optional()?
+            .filter(|user: &User| normalize_email(&user.email) == incoming_email)
+            .ok_or(MyError::Validation {
+                desc: "Invalid link".to_string(),
+            })?;
+
+        if user.email_verified_at.is_none() {
+            diesel::update(users.find(user.id))
+                .set(email_verified_at.eq(chrono::Utc::now().naive_utc()))
+                .execute(connection)?;
         }
+
+        Ok(())
+    }
+
+    /// Set `email_normalized` of every user to `normalize_email` of the email, except for the
+    /// accounts colliding once normalized, which are reported and keep it null.
+    pub async fn normalize_emails(pool: &DbPool) -> Result<NormalizeEmailsReport, MyError> {
+        use crate::schema::users::dsl::{email, email_normalized, id};
+
+        pool.run(move |connection| {
+            connection.transaction::<_, MyError, _>(|| {
+                let rows: Vec<(Uuid, String, Option<String>)> = users
+                    .select((id, email, email_normalized))
+                    .order(id)
+                    .load(connection)?;
+
+                let mut holders: HashMap<String, Vec<CollidingAccount>> = HashMap::new();
+                for (user_id, user_email, stored) in rows {
+                    holders
+                        .entry(normalize_email(&user_email))
+                        .or_default()
+                        .push(CollidingAccount {
+                            id: user_id,
+                            email: user_email,
+                            stored,
+                        });
+                }
+
+                let mut collisions = Vec::new();
+                let mut updates = Vec::new();
+                for (normalized, mut accounts) in holders {
+                    if accounts.len() > 1 {
+                        collisions.push(EmailCollision {
+                            email_normalized: normalized,
+                            accounts,
+                        });
+                    } else if let Some(account) = accounts.pop() {
+                        if account.stored.as_deref() != Some(normalized.as_str()) {
+                            updates.push((account.id, normalized));
+                        }
+                    }
+                }
+
+                // Cleared first, so that a value moving from one user to another never collides
+                let ids: Vec<Uuid> = updates.iter().map(|(user_id, _)| *user_id).collect();
+                diesel::update(users.filter(id.eq_any(ids)))
+                    .set(email_normalized.eq(None::<String>))
+                    .execute(connection)?;
+                for (user_id, normalized) in &updates {
+                    diesel::update(users.find(user_id))
+                        .set(email_normalized.eq(normalized))
+                        .execute(connection)?;
+                }
+
+                collisions.sort_by(|a, b| a.email_normalized.cmp(&b.email_normalized));
+                Ok(NormalizeEmailsReport {
+                    updated: updates.len(),
+                    collisions,
+                })
+            })
+        })
+        .await
+    }
+
+    /// Set the preferred language of the emails sent to a user
+    pub async fn update_locale(
+        incoming_id: Uuid,
+        incoming_locale: String,
+        pool: &DbPool,
+    ) -> Result<UserDTO, MyError> {
+        use crate::schema::users::dsl::locale;
+
+        pool.run(move |connection| {
+            let feedback: User = diesel::update(users.find(incoming_id))
+                .set(locale.eq(incoming_locale))
+                .get_result(connection)
+                .optional()?
+                .ok_or(MyError::NotFound {
+                    desc: "User not found".to_string(),
+                })?;
+
+            Ok(feedback.as_dto())
+        })
+        .await
     }
 
+    /// Replace the password of a user, queuing the `notification` email in the same transaction.
+    /// The current password and the last `PASSWORD_HISTORY` ones are refused, the replaced hash
+    /// joins the history and entries beyond that limit are pruned.
+    /// Every remembered device and every session but the one of `current_token` are signed out
+    /// with it, returns the number of revoked sessions.
     pub async fn update_password(
         incoming_id: Uuid,
         incoming_password: String,
+        current_token: Option<String>,
+        notification: Email,
+        hasher: &Hasher,
+        pool: &DbPool,
+    ) -> Result<usize, MyError> {
+        let history_size = PasswordPolicy::from_env().history_size;
+        let hashed_password =
+            User::hash_unused_password(incoming_id, incoming_password, history_size, hasher, pool)
+                .await?;
+        pool.run(move |connection| {
+            connection.transaction::<_, MyError, _>(|| {
+                User::store_password(incoming_id, hashed_password, history_size, connection)?;
+                TrustedDevice::delete_all(incoming_id, connection)?;
+                let revoked = UserToken::delete_others(incoming_id, current_token, connection)?;
+                OutboxEmail::enqueue(notification, connection)?;
+                Ok(revoked)
+            })
+        })
+        .await
+    }
+
+    /// Replace the password of a user with a reset token, like `update_password`.
+    /// The token is consumed in the same transaction, and every session and remembered device
+    /// of the user is signed out.
+    pub async fn reset_password(
+        incoming_id: Uuid,
+        incoming_token_hash: String,
+        incoming_password: String,
+        hasher: &Hasher,
         pool: &DbPool,
     ) -> Result<(), MyError> {
-        use crate::schema::users::dsl::id;
+        let history_size = PasswordPolicy::from_env().history_size;
+        let hashed_password =
+            User::hash_unused_password(incoming_id, incoming_password, history_size, hasher, pool)
+                .await?;
+        pool.run(move |connection| {
+            connection.transaction::<_, MyError, _>(|| {
+                Reset::consume(incoming_token_hash, connection)?;
+                User::store_password(incoming_id, hashed_password, history_size, connection)?;
+                TrustedDevice::delete_all(incoming_id, connection)?;
+                UserToken::delete_others(incoming_id, None, connection)?;
+                Ok(())
+            })
+        })
+        .await
+    }
+
+    /// Hash `incoming_password`, refusing the current password and the last `history_size` ones
+    async fn hash_unused_password(
+        incoming_id: Uuid,
+        incoming_password: String,
+        history_size: usize,
+        hasher: &Hasher,
+        pool: &DbPool,
+    ) -> Result<String, MyError> {
+        let current = pool
+            .run(move |connection| {
+                users
+                    .find(incoming_id)
+                    .first::<User>(connection)
+                    .optional()?
+                    .ok_or(MyError::NotFound {
+                        desc: "User not found".to_string(),
+                    })
+            })
+            .await?;
+        let history = PasswordHistory::find_by_user(incoming_id, history_size, pool).await?;
+
+        let previous_hashes = std::iter::once(current.password)
+            .chain(history.into_iter().map(|entry| entry.password));
+        for previous_hash in previous_hashes {
+            if hasher
+                .verify(incoming_password.clone(), previous_hash)
+                .await?
+            {
+                return Err(MyError::InvalidFields {
+                    errors: vec![FieldError {
+                        field: "password".to_string(),
+                        code: "reused".to_string(),
+                        message: "Choose a password you have not used recently".to_string(),
+                    }],
+                });
+            }
+        }
+
+        hasher.hash(incoming_password).await
+    }
+
+    /// Set the password of a user, moving the replaced hash into the history
+    fn store_password(
+        incoming_id: Uuid,
+        hashed_password: String,
+        history_size: usize,
+        connection: &PgConnection,
+    ) -> Result<(), MyError> {
         use crate::schema::users::dsl::password;
-        let hashed_password = hash(&incoming_password, DEFAULT_COST).unwrap();
 
-        let connection = pool.get().unwrap();
-        diesel::update(users)
-            .filter(id.eq(incoming_id))
+        let user: User = users.find(incoming_id).first(connection)?;
+        diesel::update(users.find(incoming_id))
             .set(password.eq(hashed_password))
-            .execute(&connection)
-            .map_err(|e| MyError::General {
-                desc: format!("{}", e),
-            })
-            .unwrap();
+            .execute(connection)?;
+
+        if history_size > 0 {
+            PasswordHistory::push(
+                PasswordHistory {
+                    id: Uuid::new_v4(),
+                    user_id: incoming_id,
+                    password: user.password,
+                    created_at: chrono::Utc::now().naive_utc(),
+                },
+                connection,
+            )?;
+        }
+        PasswordHistory::prune(incoming_id, history_size, connection)
+    }
+}
+
+impl PasswordHistory {
+    /// The `limit` most recent previous passwords of a user, newest first
+    pub async fn find_by_user(
+        incoming_user_id: Uuid,
+        limit: usize,
+        pool: &DbPool,
+    ) -> Result<Vec<PasswordHistory>, MyError> {
+        use crate::schema::password_history::{created_at, user_id};
+
+        pool.run(move |connection| {
+            Ok(password_history
+                .filter(user_id.eq(incoming_user_id))
+                .order(created_at.desc())
+                .limit(limit as i64)
+                .load::<PasswordHistory>(connection)?)
+        })
+        .await
+    }
+
+    fn push(incoming: PasswordHistory, connection: &PgConnection) -> Result<(), MyError> {
+        diesel::insert_into(password_history)
+            .values(&incoming)
+            .execute(connection)?;
+
+        Ok(())
+    }
+
+    /// Delete the entries of a user beyond the `keep` most recent ones
+    fn prune(
+        incoming_user_id: Uuid,
+        keep: usize,
+        connection: &PgConnection,
+    ) -> Result<(), MyError> {
+        use crate::schema::password_history::{created_at, id, user_id};
+
+        let stale: Vec<Uuid> = password_history
+            .filter(user_id.eq(incoming_user_id))
+            .order(created_at.desc())
+            .offset(keep as i64)
+            .select(id)
+            .load(connection)?;
+        diesel::delete(password_history.filter(id.eq_any(stale))).execute(connection)?;
 
         Ok(())
     }
@@ -99,16 +517,23 @@ impl User {
 
 impl UserToken {
     pub async fn insert(incoming: UserToken, pool: &DbPool) -> Result<UserToken, MyError> {
-        let connection = pool.get().unwrap();
-        let user: User = users.find(&incoming.user_id).first(&connection).unwrap();
+        pool.run(move |connection| {
+            let user: User = users
+                .find(&incoming.user_id)
+                .first(connection)
+                .optional()?
+                .ok_or(MyError::NotFound {
+                    desc: "User not found".to_string(),
+                })?;
 
-        let user_token_object: UserToken =
-            diesel::insert_into(crate::schema::user_token::dsl::user_token)
-                .values(&incoming)
-                .get_result(&connection)
-                .unwrap();
+            let user_token_object: UserToken =
+                diesel::insert_into(crate::schema::user_token::dsl::user_token)
+                    .values(&incoming)
+                    .get_result(connection)?;
 
-        Ok(user_token_object)
+            Ok(user_token_object)
+        })
+        .await
     }
 
     /// Find a token related to a user_id
@@ -120,17 +545,34 @@ impl UserToken {
         use crate::schema::user_token::token;
         use crate::schema::user_token::user_id;
 
-        let connection = pool.get().unwrap();
-        let user_token_object = user_token
-            .filter(user_id.eq(incoming_user_id))
-            .filter(token.eq(incoming_token))
-            .first(&connection)
-            .map_err(|e| MyError::General {
-                desc: format!("{}", e),
-            })
-            .unwrap();
+        pool.run(move |connection| {
+            let user_token_object = user_token
+                .filter(user_id.eq(incoming_user_id))
+                .filter(token.eq(incoming_token))
+                .first(connection)
+                .optional()?
+                .ok_or(MyError::NotFound {
+                    desc: "Token not found".to_string(),
+                })?;
 
-        Ok(user_token_object)
+            Ok(user_token_object)
+        })
+        .await
+    }
+
+    /// Delete every session of a user except the one of `current_token`, if any, on `connection`
+    /// inside the transaction of the password change
+    pub fn delete_others(
+        incoming_user_id: Uuid,
+        current_token: Option<String>,
+        connection: &PgConnection,
+    ) -> Result<usize, MyError> {
+        use crate::schema::user_token::{token, user_id};
+
+        let sessions = user_token
+            .filter(user_id.eq(incoming_user_id))
+            .filter(token.ne(current_token.unwrap_or_default()));
+        Ok(diesel::delete(sessions).execute(connection)?)
     }
 
     /// Delete user_token by refresh_token
@@ -140,55 +582,825 @@ impl UserToken {
         use crate::schema::user_token::token;
         use crate::schema::user_token::user_id;
 
-        let connection = pool.get().unwrap();
-        diesel::delete(user_token.filter(token.eq(incoming_token)))
-            .execute(&connection)
-            .map_err(|e| MyError::General {
-                desc: format!("{}", e),
+        pool.run(move |connection| {
+            diesel::delete(user_token.filter(token.eq(incoming_token))).execute(connection)?;
+
+            Ok(())
+        })
+        .await
+    }
+}
+
+impl EmailVerification {
+    /// Store a new verification, invalidating the earlier ones of the same user and the expired
+    /// ones. `verification_email`, holding the link, is queued in the same transaction.
+    pub async fn insert(
+        incoming: EmailVerification,
+        verification_email: Email,
+        pool: &DbPool,
+    ) -> Result<(), MyError> {
+        use crate::schema::email_verification::{expires_at, user_id};
+
+        pool.run(move |connection| {
+            connection.transaction::<_, MyError, _>(|| {
+                diesel::delete(
+                    email_verification.filter(
+                        user_id
+                            .eq(incoming.user_id)
+                            .or(expires_at.lt(incoming.created_at)),
+                    ),
+                )
+                .execute(connection)?;
+
+                diesel::insert_into(email_verification)
+                    .values(&incoming)
+                    .execute(connection)?;
+                OutboxEmail::enqueue(verification_email, connection)?;
+                Ok(())
             })
-            .unwrap();
+        })
+        .await
+    }
 
-        Ok(())
+    /// Verify the email of the user a link was sent to, by the hash of its token.
+    /// Fails if the token is unknown, expired or already used, or the user changed email since.
+    pub async fn verify(incoming_token_hash: String, pool: &DbPool) -> Result<(), MyError> {
+        use crate::schema::email_verification::{expires_at, token_hash, used_at};
+
+        let now = chrono::Utc::now().naive_utc();
+        pool.run(move |connection| {
+            connection.transaction::<_, MyError, _>(|| {
+                let verification: EmailVerification = diesel::update(
+                    email_verification
+                        .filter(token_hash.eq(incoming_token_hash))
+                        .filter(used_at.is_null())
+                        .filter(expires_at.gt(now)),
+                )
+                .set(used_at.eq(now))
+                .get_result(connection)
+                .optional()?
+                .ok_or(MyError::Validation {
+                    desc: "Invalid link".to_string(),
+                })?;
+
+                User::mark_verified(verification.user_id, verification.email, connection)
+            })
+        })
+        .await
     }
 }
 
 impl Reset {
+    /// Store a new reset, invalidating the earlier ones of the same email and the expired ones.
+    /// `reset_email`, holding the link, is queued in the same transaction.
     pub async fn insert(
-        incoming_email: String,
-        incoming_token: String,
+        incoming: Reset,
+        reset_email: Email,
         pool: &DbPool,
     ) -> Result<Reset, MyError> {
-        let connection = pool.get().unwrap();
+        use crate::schema::reset::{email, expires_at};
+
+        pool.run(move |connection| {
+            connection.transaction::<_, MyError, _>(|| {
+                diesel::delete(
+                    reset_schema.filter(
+                        email
+                            .eq(&incoming.email)
+                            .or(expires_at.lt(incoming.created_at)),
+                    ),
+                )
+                .execute(connection)?;
 
-        let reset = Reset {
-            token: incoming_token,
-            email: incoming_email,
+                let feedback = diesel::insert_into(reset_schema)
+                    .values(&incoming)
+                    .get_result(connection)?;
+                OutboxEmail::enqueue(reset_email, connection)?;
+                Ok(feedback)
+            })
+        })
+        .await
+    }
+
+    /// Find a reset by the hash of its token.
+    /// Fails if the token is unknown, expired or already used.
+    pub async fn find_valid(incoming_token_hash: String, pool: &DbPool) -> Result<Reset, MyError> {
+        use crate::schema::reset::{expires_at, token_hash, used_at};
+
+        let now = chrono::Utc::now().naive_utc();
+        pool.run(move |connection| {
+            reset_schema
+                .filter(token_hash.eq(incoming_token_hash))
+                .filter(used_at.is_null())
+                .filter(expires_at.gt(now))
+                .first(connection)
+                .optional()?
+                .ok_or(MyError::Validation {
+                    desc: "Invalid link".to_string(),
+                })
+        })
+        .await
+    }
+
+    /// Mark a reset as used, so its token only changes the password once
+    fn consume(incoming_token_hash: String, connection: &PgConnection) -> Result<Reset, MyError> {
+        use crate::schema::reset::{expires_at, token_hash, used_at};
+
+        let now = chrono::Utc::now().naive_utc();
+        diesel::update(
+            reset_schema
+                .filter(token_hash.eq(incoming_token_hash))
+                .filter(used_at.is_null())
+                .filter(expires_at.gt(now)),
+        )
+        .set(used_at.eq(now))
+        .get_result(connection)
+        .optional()?
+        .ok_or(MyError::Validation {
+            desc: "Invalid link".to_string(),
+        })
+    }
+}
+
+impl LoginFailure {
+    /// Fail with the longest wait if any of `keys` is blocked
+    pub async fn check(keys: Vec<String>, pool: &DbPool) -> Result<(), MyError> {
+        use crate::schema::login_failure::{blocked_until, key};
+
+        let now = chrono::Utc::now().naive_utc();
+        let until: Option<chrono::NaiveDateTime> = pool
+            .run(move |connection| {
+                Ok(login_failure
+                    .filter(key.eq_any(keys))
+                    .filter(blocked_until.gt(now))
+                    .select(diesel::dsl::max(blocked_until))
+                    .first(connection)?)
+            })
+            .await?;
+
+        match until {
+            Some(until) => Err(MyError::RateLimited {
+                retry_after: (until - now).num_seconds() + 1,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Count a failed login for `incoming_key` and block the next ones following `policy`.
+    /// Returns the counter and whether this failure started a lockout.
+    pub async fn record(
+        incoming_key: String,
+        thresholds: Thresholds,
+        policy: LockoutPolicy,
+        pool: &DbPool,
+    ) -> Result<(LoginFailure, bool), MyError> {
+        use crate::schema::login_failure::{
+            blocked_until, failures, last_failure_at, locked_at, unlock_token_hash,
         };
 
-        let reset_object = diesel::insert_into(crate::schema::reset::dsl::reset)
-            .values(&reset)
-            .get_result(&connection)
-            .map_err(|e| MyError::General {
-                desc: format!("{}", e),
+        let now = chrono::Utc::now().naive_utc();
+        pool.run(move |connection| {
+            connection.transaction::<_, MyError, _>(|| {
+                diesel::insert_into(login_failure)
+                    .values(&LoginFailure {
+                        key: incoming_key.clone(),
+                        failures: 0,
+                        last_failure_at: now,
+                        blocked_until: None,
+                        locked_at: None,
+                        unlock_token_hash: None,
+                    })
+                    .on_conflict_do_nothing()
+                    .execute(connection)?;
+                let mut counter: LoginFailure = login_failure
+                    .find(&incoming_key)
+                    .for_update()
+                    .first(connection)?;
+
+                // Start over once the previous failures are old enough
+                if counter.last_failure_at < now - policy.window {
+                    counter.failures = 0;
+                    counter.locked_at = None;
+                    counter.unlock_token_hash = None;
+                }
+                counter.failures += 1;
+                counter.last_failure_at = now;
+
+                let block = policy.block(thresholds, counter.failures);
+                counter.blocked_until = block.until(now);
+                let started_lockout =
+                    matches!(block, Block::Lockout(_)) && counter.locked_at.is_none();
+                if started_lockout {
+                    counter.locked_at = Some(now);
+                }
+
+                let counter = diesel::update(login_failure.find(&incoming_key))
+                    .set((
+                        failures.eq(counter.failures),
+                        last_failure_at.eq(counter.last_failure_at),
+                        blocked_until.eq(counter.blocked_until),
+                        locked_at.eq(counter.locked_at),
+                        unlock_token_hash.eq(counter.unlock_token_hash),
+                    ))
+                    .get_result(connection)?;
+
+                Ok((counter, started_lockout))
+            })
+        })
+        .await
+    }
+
+    pub async fn find(
+        incoming_key: String,
+        pool: &DbPool,
+    ) -> Result<Option<LoginFailure>, MyError> {
+        pool.run(move |connection| {
+            Ok(login_failure
+                .find(incoming_key)
+                .first(connection)
+                .optional()?)
+        })
+        .await
+    }
+
+    /// Store the hash of the token of an unlock link, replacing the previous one.
+    /// `email`, holding the link, is queued in the same transaction.
+    pub async fn set_unlock_token(
+        incoming_key: String,
+        incoming_token_hash: String,
+        email: Email,
+        pool: &DbPool,
+    ) -> Result<(), MyError> {
+        use crate::schema::login_failure::unlock_token_hash;
+
+        pool.run(move |connection| {
+            connection.transaction::<_, MyError, _>(|| {
+                diesel::update(login_failure.find(incoming_key))
+                    .set(unlock_token_hash.eq(incoming_token_hash))
+                    .execute(connection)?;
+
+                OutboxEmail::enqueue(email, connection)
+            })
+        })
+        .await
+    }
+
+    /// Forget the failures of `incoming_key`, after a successful login or an unlock
+    pub async fn clear(incoming_key: String, pool: &DbPool) -> Result<(), MyError> {
+        pool.run(move |connection| {
+            diesel::delete(login_failure.find(incoming_key)).execute(connection)?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    /// Forget the failures of the account an unlock link was sent for
+    pub async fn unlock(
+        incoming_token_hash: String,
+        pool: &DbPool,
+    ) -> Result<LoginFailure, MyError> {
+        use crate::schema::login_failure::unlock_token_hash;
+
+        pool.run(move |connection| {
+            diesel::delete(login_failure.filter(unlock_token_hash.eq(incoming_token_hash)))
+                .get_result(connection)
+                .optional()?
+                .ok_or(MyError::Validation {
+                    desc: "Invalid link".to_string(),
+                })
+        })
+        .await
+    }
+}
+
+impl LoginLink {
+    /// Store a new sign-in link, `email` holding it is queued in the same transaction
+    pub async fn insert(
+        incoming: LoginLink,
+        email: Email,
+        pool: &DbPool,
+    ) -> Result<LoginLink, MyError> {
+        pool.run(move |connection| {
+            connection.transaction::<_, MyError, _>(|| {
+                let feedback = diesel::insert_into(login_link)
+                    .values(&incoming)
+                    .get_result(connection)?;
+                OutboxEmail::enqueue(email, connection)?;
+                Ok(feedback)
+            })
+        })
+        .await
+    }
+
+    /// Mark a sign-in link as used and return it.
+    /// Fails if the link is unknown, expired, already used or was requested from another browser.
+    pub async fn consume(
+        incoming_token: String,
+        incoming_state: String,
+        pool: &DbPool,
+    ) -> Result<LoginLink, MyError> {
+        use crate::schema::login_link::{expires_at, state, token, used_at};
+
+        let now = chrono::Utc::now().naive_utc();
+        pool.run(move |connection| {
+            diesel::update(
+                login_link
+                    .filter(token.eq(incoming_token))
+                    .filter(state.eq(incoming_state))
+                    .filter(used_at.is_null())
+                    .filter(expires_at.gt(now)),
+            )
+            .set(used_at.eq(now))
+            .get_result(connection)
+            .optional()?
+            .ok_or(MyError::Unauthenticated {
+                desc: "Invalid link".to_string(),
+            })
+        })
+        .await
+    }
+}
+
+impl PendingLogin {
+    pub async fn insert(incoming: PendingLogin, pool: &DbPool) -> Result<PendingLogin, MyError> {
+        pool.run(move |connection| {
+            Ok(diesel::insert_into(pending_login)
+                .values(&incoming)
+                .get_result(connection)?)
+        })
+        .await
+    }
+
+    /// Find a pending login by its code, only for the browser which created it
+    pub async fn find_by_code(
+        incoming_code: String,
+        incoming_state: String,
+        pool: &DbPool,
+    ) -> Result<PendingLogin, MyError> {
+        use crate::schema::pending_login::{code, state};
+
+        pool.run(move |connection| {
+            pending_login
+                .filter(code.eq(incoming_code))
+                .filter(state.eq(incoming_state))
+                .first(connection)
+                .optional()?
+                .ok_or(MyError::NotFound {
+                    desc: "Code not found".to_string(),
+                })
+        })
+        .await
+    }
+
+    /// Approve a pending login on behalf of `incoming_user_id`.
+    /// Fails if the code is unknown, expired or already approved.
+    pub async fn approve(
+        incoming_code: String,
+        incoming_user_id: Uuid,
+        pool: &DbPool,
+    ) -> Result<PendingLogin, MyError> {
+        use crate::schema::pending_login::{approved_at, code, expires_at, user_id};
+
+        let now = chrono::Utc::now().naive_utc();
+        pool.run(move |connection| {
+            diesel::update(
+                pending_login
+                    .filter(code.eq(incoming_code))
+                    .filter(approved_at.is_null())
+                    .filter(expires_at.gt(now)),
+            )
+            .set((user_id.eq(incoming_user_id), approved_at.eq(now)))
+            .get_result(connection)
+            .optional()?
+            .ok_or(MyError::NotFound {
+                desc: "Invalid code".to_string(),
+            })
+        })
+        .await
+    }
+
+    /// Mark an approved login as claimed, so it can only start one session
+    pub async fn claim(
+        incoming_code: String,
+        incoming_state: String,
+        pool: &DbPool,
+    ) -> Result<PendingLogin, MyError> {
+        use crate::schema::pending_login::{approved_at, claimed_at, code, state};
+
+        let now = chrono::Utc::now().naive_utc();
+        pool.run(move |connection| {
+            diesel::update(
+                pending_login
+                    .filter(code.eq(incoming_code))
+                    .filter(state.eq(incoming_state))
+                    .filter(approved_at.is_not_null())
+                    .filter(claimed_at.is_null()),
+            )
+            .set(claimed_at.eq(now))
+            .get_result(connection)
+            .optional()?
+            .ok_or(MyError::Gone {
+                desc: "Code already used".to_string(),
+            })
+        })
+        .await
+    }
+}
+
+impl TrustedDevice {
+    pub async fn insert(incoming: TrustedDevice, pool: &DbPool) -> Result<TrustedDevice, MyError> {
+        pool.run(move |connection| {
+            Ok(diesel::insert_into(trusted_device)
+                .values(&incoming)
+                .get_result(connection)?)
+        })
+        .await
+    }
+
+    /// Record that a remembered device was seen again.
+    /// Fails if the device was revoked, expired or belongs to another user.
+    pub async fn touch(
+        incoming_id: Uuid,
+        incoming_user_id: Uuid,
+        pool: &DbPool,
+    ) -> Result<TrustedDevice, MyError> {
+        use crate::schema::trusted_device::{expires_at, id, last_seen_at, user_id};
+
+        let now = chrono::Utc::now().naive_utc();
+        pool.run(move |connection| {
+            diesel::update(
+                trusted_device
+                    .filter(id.eq(incoming_id))
+                    .filter(user_id.eq(incoming_user_id))
+                    .filter(expires_at.gt(now)),
+            )
+            .set(last_seen_at.eq(now))
+            .get_result(connection)
+            .optional()?
+            .ok_or(MyError::NotFound {
+                desc: "Device not found".to_string(),
+            })
+        })
+        .await
+    }
+
+    /// List the devices remembered for a user, most recently seen first
+    pub async fn find_by_user(
+        incoming_user_id: Uuid,
+        pool: &DbPool,
+    ) -> Result<Vec<TrustedDevice>, MyError> {
+        use crate::schema::trusted_device::{last_seen_at, user_id};
+
+        pool.run(move |connection| {
+            Ok(trusted_device
+                .filter(user_id.eq(incoming_user_id))
+                .order(last_seen_at.desc())
+                .load::<TrustedDevice>(connection)?)
+        })
+        .await
+    }
+
+    /// Revoke one remembered device of a user
+    pub async fn delete(
+        incoming_id: Uuid,
+        incoming_user_id: Uuid,
+        pool: &DbPool,
+    ) -> Result<(), MyError> {
+        use crate::schema::trusted_device::{id, user_id};
+
+        pool.run(move |connection| {
+            let deleted = diesel::delete(
+                trusted_device
+                    .filter(id.eq(incoming_id))
+                    .filter(user_id.eq(incoming_user_id)),
+            )
+            .execute(connection)?;
+
+            match deleted {
+                0 => Err(MyError::NotFound {
+                    desc: "Device not found".to_string(),
+                }),
+                _ => Ok(()),
+            }
+        })
+        .await
+    }
+
+    /// Revoke every remembered device of a user on `connection`, inside the transaction of the
+    /// password change
+    pub fn delete_all(incoming_user_id: Uuid, connection: &PgConnection) -> Result<(), MyError> {
+        use crate::schema::trusted_device::user_id;
+
+        diesel::delete(trusted_device.filter(user_id.eq(incoming_user_id))).execute(connection)?;
+
+        Ok(())
+    }
+}
+
+impl RateLimitBucket {
+    /// Take a token from the bucket of `incoming_key`, holding up to `capacity` tokens and gaining
+    /// `per_second` of them every second. A new bucket starts full.
+    pub async fn take(
+        incoming_key: String,
+        capacity: f64,
+        per_second: f64,
+        pool: &DbPool,
+    ) -> Result<RateLimitBucket, MyError> {
+        use diesel::sql_types::{Double, Text};
+
+        pool.run(move |connection| {
+            // Refilled in the same statement, so concurrent requests never share a token
+            let refill =
+                "least($2, bucket.tokens + extract(epoch from now() - bucket.updated_at)::float8 * $3)";
+            Ok(diesel::sql_query(format!(
+                "insert into rate_limit_bucket as bucket (key, tokens, allowed, updated_at) \
+                 values ($1, $2 - 1, true, now()) \
+                 on conflict (key) do update set \
+                     tokens = {refill} - case when {refill} >= 1 then 1 else 0 end, \
+                     allowed = {refill} >= 1, \
+                     updated_at = now() \
+                 returning key, tokens, allowed"
+            ))
+            .bind::<Text, _>(incoming_key)
+            .bind::<Double, _>(capacity)
+            .bind::<Double, _>(per_second)
+            .get_result(connection)?)
+        })
+        .await
+    }
+
+    /// Delete the buckets untouched for `idle`, they would be full again anyway
+    pub async fn prune(idle: chrono::Duration, pool: &DbPool) -> Result<usize, MyError> {
+        use crate::schema::rate_limit_bucket::dsl::{rate_limit_bucket, updated_at};
+
+        let before = chrono::Utc::now().naive_utc() - idle;
+        pool.run(move |connection| {
+            Ok(
+                diesel::delete(rate_limit_bucket.filter(updated_at.lt(before)))
+                    .execute(connection)?,
+            )
+        })
+        .await
+    }
+}
+
+impl UsedChallenge {
+    /// Record a solved challenge, failing if it was already used.
+    /// Expired ones are deleted on the way, they are refused for their expiry anyway.
+    pub async fn insert(incoming: UsedChallenge, pool: &DbPool) -> Result<(), MyError> {
+        use crate::schema::used_challenge::dsl::{expires_at, used_challenge};
+
+        let now = chrono::Utc::now().naive_utc();
+        pool.run(move |connection| {
+            diesel::delete(used_challenge.filter(expires_at.lt(now))).execute(connection)?;
+            diesel::insert_into(used_challenge)
+                .values(&incoming)
+                .execute(connection)
+                .map_err(|e| match MyError::from(e) {
+                    MyError::Conflict { .. } => MyError::ChallengeRequired {
+                        desc: "Challenge already used".to_string(),
+                    },
+                    e => e,
+                })?;
+
+            Ok(())
+        })
+        .await
+    }
+}
+
+impl OutboxEmail {
+    /// Queue `email` on `connection`, inside the transaction of the change it tells about
+    pub fn enqueue(email: Email, connection: &PgConnection) -> Result<(), MyError> {
+        let now = chrono::Utc::now().naive_utc();
+        let incoming = OutboxEmail {
+            id: Uuid::new_v4(),
+            recipient: email.to,
+            subject: email.subject,
+            html_body: email.html_body,
+            text_body: email.text_body,
+            attempts: 0,
+            next_attempt_at: now,
+            last_error: None,
+            created_at: now,
+            sent_at: None,
+            dead_at: None,
+        };
+        diesel::insert_into(email_outbox)
+            .values(&incoming)
+            .execute(connection)?;
+
+        Ok(())
+    }
+
+    /// Queue an email which goes with no other change
+    pub async fn insert(email: Email, pool: &DbPool) -> Result<(), MyError> {
+        pool.run(move |connection| OutboxEmail::enqueue(email, connection))
+            .await
+    }
+
+    /// Take up to `limit` due emails, hidden from other dispatchers for `lease`.
+    /// Rows claimed by a concurrent dispatcher are skipped rather than waited for.
+    pub async fn claim(
+        limit: i64,
+        lease: chrono::Duration,
+        pool: &DbPool,
+    ) -> Result<Vec<OutboxEmail>, MyError> {
+        use crate::schema::email_outbox::{dead_at, id, next_attempt_at, sent_at};
+
+        let now = chrono::Utc::now().naive_utc();
+        pool.run(move |connection| {
+            connection.transaction::<_, MyError, _>(|| {
+                let due: Vec<OutboxEmail> = email_outbox
+                    .filter(sent_at.is_null())
+                    .filter(dead_at.is_null())
+                    .filter(next_attempt_at.le(now))
+                    .order(next_attempt_at.asc())
+                    .limit(limit)
+                    .for_update()
+                    .skip_locked()
+                    .load(connection)?;
+
+                let ids: Vec<Uuid> = due.iter().map(|email| email.id).collect();
+                diesel::update(email_outbox.filter(id.eq_any(ids)))
+                    .set(next_attempt_at.eq(now + lease))
+                    .execute(connection)?;
+
+                Ok(due)
             })
-            .unwrap();
+        })
+        .await
+    }
+
+    /// Mark an email as delivered, dropping its body which may hold a token
+    pub async fn mark_sent(incoming_id: Uuid, pool: &DbPool) -> Result<(), MyError> {
+        use crate::schema::email_outbox::{html_body, last_error, sent_at, text_body};
+
+        let now = chrono::Utc::now().naive_utc();
+        pool.run(move |connection| {
+            diesel::update(email_outbox.find(incoming_id))
+                .set((
+                    sent_at.eq(now),
+                    html_body.eq(""),
+                    text_body.eq(""),
+                    last_error.eq(None::<String>),
+                ))
+                .execute(connection)?;
 
-        Ok(reset_object)
+            Ok(())
+        })
+        .await
     }
 
-    /// Find a Reset object related to a token
-    pub async fn find_by_token(incoming_token: String, pool: &DbPool) -> Result<Reset, MyError> {
-        use crate::schema::reset::token;
+    /// Count a failed delivery, retried at `retry_at` or given up when it is `None`.
+    /// A dead email drops its body like a delivered one, the links it holds have expired by then.
+    pub async fn mark_failed(
+        incoming_id: Uuid,
+        error: String,
+        retry_at: Option<chrono::NaiveDateTime>,
+        pool: &DbPool,
+    ) -> Result<(), MyError> {
+        use crate::schema::email_outbox::{
+            attempts, dead_at, html_body, last_error, next_attempt_at, text_body,
+        };
+
+        let now = chrono::Utc::now().naive_utc();
+        pool.run(move |connection| {
+            connection.transaction::<_, MyError, _>(|| {
+                diesel::update(email_outbox.find(incoming_id))
+                    .set((
+                        attempts.eq(attempts + 1),
+                        last_error.eq(error),
+                        next_attempt_at.eq(retry_at.unwrap_or(now)),
+                        dead_at.eq(retry_at.map_or(Some(now), |_| None)),
+                    ))
+                    .execute(connection)?;
+                if retry_at.is_none() {
+                    diesel::update(email_outbox.find(incoming_id))
+                        .set((html_body.eq(""), text_body.eq("")))
+                        .execute(connection)?;
+                }
 
-        let connection = pool.get().unwrap();
-        let reset_object = reset_schema
-            .filter(token.eq(incoming_token))
-            .first(&connection)
-            .map_err(|e| MyError::General {
-                desc: format!("{}", e),
+                Ok(())
             })
-            .unwrap();
+        })
+        .await
+    }
+
+    /// Delay an email until `until` without counting an attempt
+    pub async fn postpone(
+        incoming_id: Uuid,
+        until: chrono::NaiveDateTime,
+        pool: &DbPool,
+    ) -> Result<(), MyError> {
+        use crate::schema::email_outbox::next_attempt_at;
+
+        pool.run(move |connection| {
+            diesel::update(email_outbox.find(incoming_id))
+                .set(next_attempt_at.eq(until))
+                .execute(connection)?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    /// When emails were delivered to `incoming_recipient` since `since`, oldest first
+    pub async fn sent_to(
+        incoming_recipient: String,
+        since: chrono::NaiveDateTime,
+        pool: &DbPool,
+    ) -> Result<Vec<chrono::NaiveDateTime>, MyError> {
+        use crate::schema::email_outbox::{recipient, sent_at};
+
+        pool.run(move |connection| {
+            let sent: Vec<Option<chrono::NaiveDateTime>> = email_outbox
+                .select(sent_at)
+                .filter(recipient.eq(incoming_recipient))
+                .filter(sent_at.gt(since))
+                .order(sent_at.asc())
+                .load(connection)?;
+
+            Ok(sent.into_iter().flatten().collect())
+        })
+        .await
+    }
+
+    /// The last `limit` emails with `status` (`pending`, `sent` or `dead`), newest first
+    pub async fn find(
+        status: Option<String>,
+        limit: i64,
+        pool: &DbPool,
+    ) -> Result<Vec<OutboxEmail>, MyError> {
+        use crate::schema::email_outbox::{created_at, dead_at, sent_at};
+
+        pool.run(move |connection| {
+            let mut query = email_outbox.into_boxed();
+            query = match status.as_deref() {
+                None => query,
+                Some("pending") => query.filter(sent_at.is_null()).filter(dead_at.is_null()),
+                Some("sent") => query.filter(sent_at.is_not_null()),
+                Some("dead") => query.filter(dead_at.is_not_null()),
+                Some(other) => {
+                    return Err(MyError::Validation {
+                        desc: format!("Unknown status {other}, use pending, sent or dead"),
+                    })
+                }
+            };
+
+            Ok(query
+                .order(created_at.desc())
+                .limit(limit)
+                .load(connection)?)
+        })
+        .await
+    }
+
+    /// Queue dead emails again, the one with `incoming_id` or all of them.
+    /// Those whose body was dropped are left dead, they would go out empty.
+    pub async fn replay(incoming_id: Option<Uuid>, pool: &DbPool) -> Result<usize, MyError> {
+        use crate::schema::email_outbox::{
+            attempts, dead_at, html_body, id, next_attempt_at, text_body,
+        };
+
+        let now = chrono::Utc::now().naive_utc();
+        pool.run(move |connection| {
+            let mut query = diesel::update(email_outbox)
+                .filter(dead_at.is_not_null())
+                .filter(html_body.ne("").or(text_body.ne("")))
+                .into_boxed();
+            if let Some(incoming_id) = incoming_id {
+                query = query.filter(id.eq(incoming_id));
+            }
+
+            let replayed = query
+                .set((
+                    dead_at.eq(None::<chrono::NaiveDateTime>),
+                    attempts.eq(0),
+                    next_attempt_at.eq(now),
+                ))
+                .execute(connection)?;
+            if replayed == 0 && incoming_id.is_some() {
+                return Err(MyError::NotFound {
+                    desc: "No dead email with a body and this id".to_string(),
+                });
+            }
+
+            Ok(replayed)
+        })
+        .await
+    }
+
+    /// Delete the emails delivered or dead before `before`
+    pub async fn prune(before: chrono::NaiveDateTime, pool: &DbPool) -> Result<usize, MyError> {
+        use crate::schema::email_outbox::{dead_at, sent_at};
 
-        Ok(reset_object)
+        pool.run(move |connection| {
+            Ok(
+                diesel::delete(email_outbox.filter(sent_at.lt(before).or(dead_at.lt(before))))
+                    .execute(connection)?,
+            )
+        })
+        .await
     }
 }
@@ -1,4 +1,9 @@
+use chrono::NaiveDateTime;
+use diesel::{Insertable, Queryable, QueryableByName};
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::schema::*;
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct ObjectResponse<T> {
@@ -14,3 +19,162 @@ pub struct MessageResponse<T> {
 pub struct TokenResponse<T> {
     pub token: T,
 }
+
+/// RFC 7807 problem details, the body of every error response.
+/// `code` is stable and machine-readable, the optional members only appear for some errors.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ProblemDetails {
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    pub title: String,
+    pub status: u16,
+    pub detail: String,
+    pub code: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_age: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub acr_values: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub errors: Option<Vec<FieldError>>,
+}
+
+/// What is wrong with one field of a request, `code` is stable and machine-readable
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FieldError {
+    pub field: String,
+    pub code: String,
+    pub message: String,
+}
+
+/// Token bucket of a rate limit, shared by every instance of the application.
+/// `allowed` tells whether the last request could take a token.
+#[derive(Debug, Clone, QueryableByName)]
+#[table_name = "rate_limit_bucket"]
+pub struct RateLimitBucket {
+    pub key: String,
+    pub tokens: f64,
+    pub allowed: bool,
+}
+
+/// A solved proof-of-work challenge, kept until it expires so it cannot be replayed
+#[derive(Debug, Clone, Queryable, Insertable)]
+#[table_name = "used_challenge"]
+pub struct UsedChallenge {
+    pub jti: String,
+    pub expires_at: NaiveDateTime,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChallengeQuery {
+    pub route: String,
+}
+
+/// A proof-of-work challenge: find a `nonce` such that the SHA-256 of `<challenge>:<nonce>` starts
+/// with `difficulty` zero bits, then send both in the `X-Challenge` and `X-Challenge-Nonce` headers
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChallengeResponse {
+    pub challenge: String,
+    pub algorithm: String,
+    pub difficulty: u32,
+    pub expires_at: NaiveDateTime,
+}
+
+/// An email waiting in the outbox, written in the transaction of the change it tells about.
+/// Delivered when `sent_at` is set, given up when `dead_at` is set.
+#[derive(Debug, Clone, Queryable, Insertable)]
+#[table_name = "email_outbox"]
+pub struct OutboxEmail {
+    pub id: Uuid,
+    pub recipient: String,
+    pub subject: String,
+    pub html_body: String,
+    pub text_body: String,
+    /// Failed deliveries so far
+    pub attempts: i32,
+    pub next_attempt_at: NaiveDateTime,
+    pub last_error: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub sent_at: Option<NaiveDateTime>,
+    pub dead_at: Option<NaiveDateTime>,
+}
+
+/// An outbox email as shown to administrators, without its body which may hold a token
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutboxEmailDTO {
+    pub id: Uuid,
+    pub recipient: String,
+    pub subject: String,
+    /// `pending`, `sent` or `dead`
+    pub status: String,
+    pub attempts: i32,
+    pub next_attempt_at: NaiveDateTime,
+    pub last_error: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub sent_at: Option<NaiveDateTime>,
+    pub dead_at: Option<NaiveDateTime>,
+}
+
+impl OutboxEmail {
+    pub fn status(&self) -> &'static str {
+        match (self.sent_at, self.dead_at) {
+            (Some(_), _) => "sent",
+            (None, Some(_)) => "dead",
+            (None, None) => "pending",
+        }
+    }
+
+    pub fn as_dto(&self) -> OutboxEmailDTO {
+        OutboxEmailDTO {
+            id: self.id,
+            recipient: self.recipient.clone(),
+            subject: self.subject.clone(),
+            status: self.status().to_string(),
+            attempts: self.attempts,
+            next_attempt_at: self.next_attempt_at,
+            last_error: self.last_error.clone(),
+            created_at: self.created_at,
+            sent_at: self.sent_at,
+            dead_at: self.dead_at,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutboxQuery {
+    /// `pending`, `sent` or `dead`, every email by default
+    #[serde(default)]
+    pub status: Option<String>,
+}
+
+/// An email of the dev inbox, with the links of its bodies
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DevEmailDTO {
+    pub id: u64,
+    pub to: String,
+    pub subject: String,
+    pub sent_at: NaiveDateTime,
+    pub links: Vec<String>,
+    pub text_body: String,
+    pub html_body: String,
+}
+
+/// The latest link of the dev inbox matching a `DevMailQuery`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DevMailLink {
+    pub id: u64,
+    pub subject: String,
+    pub sent_at: NaiveDateTime,
+    pub link: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DevMailQuery {
+    /// Only emails to this address, whatever its case
+    #[serde(default)]
+    pub to: Option<String>,
+    /// Only links containing this text, like `/reset/`
+    #[serde(default)]
+    pub contains: Option<String>,
+}
@@ -5,6 +5,7 @@ use diesel::{Insertable, Queryable};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use crate::entity::general::FieldError;
 use crate::schema::*;
 
 #[derive(Debug, Clone, Serialize, Deserialize, Queryable, PartialEq, Insertable)]
@@ -15,6 +16,12 @@ pub struct User {
     pub last_name: String,
     pub email: String,
     pub password: String,
+    pub email_verified_at: Option<NaiveDateTime>,
+    /// `email` as returned by `utils::normalize_email`, unique among users.
+    /// `None` until `normalize_emails` fills it after the `unique_email` migration
+    pub email_normalized: Option<String>,
+    /// Preferred language of emails, like `fr`, `Accept-Language` is used when unset
+    pub locale: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -24,12 +31,28 @@ pub struct UserRegisterationRequest {
     pub email: String,
     pub password: String,
     pub password_confirm: String,
+    /// Preferred language of emails, `Accept-Language` by default
+    #[serde(default)]
+    pub locale: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UserLoginRequest {
     pub email: String,
     pub password: String,
+    /// Remember this browser as a trusted device for 30 days
+    #[serde(default)]
+    pub remember_device: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReauthenticateRequest {
+    pub password: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VerifyResendRequest {
+    pub email: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -38,27 +61,127 @@ pub struct ForgotRequest {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoginLinkRequest {
+    pub email: String,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct ResetRequest {
     pub token: String,
     pub password: String,
     pub password_confirm: String,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LocaleRequest {
+    pub locale: String,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ChangePasswordRequest {
+    pub current_password: String,
+    pub password: String,
+    pub password_confirm: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PasswordStrengthRequest {
+    pub password: String,
+    #[serde(default)]
+    pub email: Option<String>,
+    #[serde(default)]
+    pub first_name: Option<String>,
+    #[serde(default)]
+    pub last_name: Option<String>,
+}
+
+/// Strength of a password from 0 (too guessable) to 4 (very unguessable), and the policy violations
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PasswordStrengthResponse {
+    pub score: u8,
+    pub guesses_log10: f64,
+    pub breached: bool,
+    pub valid: bool,
+    pub feedback: PasswordFeedback,
+    pub errors: Vec<FieldError>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PasswordFeedback {
+    pub warning: Option<String>,
+    pub suggestions: Vec<String>,
+}
+
+/// One user of a bulk import, with the password hash of the application it comes from
+#[derive(Clone, Serialize, Deserialize)]
+pub struct UserImportRow {
+    pub first_name: String,
+    pub last_name: String,
+    pub email: String,
+    pub password_hash: String,
+    #[serde(default)]
+    pub email_verified: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserImportReport {
+    pub imported: usize,
+    pub failed: usize,
+    pub errors: Vec<UserImportError>,
+}
+
+/// Outcome of `User::normalize_emails`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NormalizeEmailsReport {
+    pub updated: usize,
+    pub collisions: Vec<EmailCollision>,
+}
+
+/// Accounts whose emails are the same once normalized, left as they are until merged by hand
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmailCollision {
+    pub email_normalized: String,
+    pub accounts: Vec<CollidingAccount>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CollidingAccount {
+    pub id: Uuid,
+    pub email: String,
+    /// What `email_normalized` holds now, `None` until the collision is resolved
+    pub stored: Option<String>,
+}
+
+/// A row which was not imported, `line` is 1-based and counts the CSV header
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserImportError {
+    pub line: u64,
+    pub email: Option<String>,
+    pub code: String,
+    pub detail: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UserDTO {
     pub id: Uuid,
     pub first_name: String,
     pub last_name: String,
     pub email: String,
+    pub email_verified: bool,
+    pub email_verified_at: Option<NaiveDateTime>,
+    pub locale: Option<String>,
 }
 
 impl User {
     pub fn as_dto(&self) -> UserDTO {
         UserDTO {
-            id: self.id.clone(),
+            id: self.id,
             first_name: self.first_name.clone(),
             last_name: self.last_name.clone(),
             email: self.email.clone(),
+            email_verified: self.email_verified_at.is_some(),
+            email_verified_at: self.email_verified_at,
+            locale: self.locale.clone(),
         }
     }
 }
@@ -72,9 +195,106 @@ pub struct UserToken {
     pub expires_at: NaiveDateTime,
 }
 
+/// A password reset requested by email.
+/// Only the SHA-256 of the token sent in the link is stored.
 #[derive(Debug, Clone, Serialize, Deserialize, Queryable, PartialEq, Insertable)]
 #[table_name = "reset"]
 pub struct Reset {
+    pub token_hash: String,
+    pub email: String,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub used_at: Option<NaiveDateTime>,
+}
+
+/// A link proving that a user owns `email`, the normalized address the user had when it was sent.
+/// Only the SHA-256 of the token sent in the link is stored.
+#[derive(Debug, Clone, Serialize, Deserialize, Queryable, PartialEq, Insertable)]
+#[table_name = "email_verification"]
+pub struct EmailVerification {
+    pub token_hash: String,
+    pub user_id: Uuid,
+    pub email: String,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub used_at: Option<NaiveDateTime>,
+}
+
+/// Consecutive failed logins of an account (`email:<normalized email>`) or a client (`ip:<address>`).
+/// Logins are refused until `blocked_until`, `locked_at` is set once the failures reach a lockout.
+#[derive(Debug, Clone, Serialize, Deserialize, Queryable, PartialEq, Insertable)]
+#[table_name = "login_failure"]
+pub struct LoginFailure {
+    pub key: String,
+    pub failures: i32,
+    pub last_failure_at: NaiveDateTime,
+    pub blocked_until: Option<NaiveDateTime>,
+    pub locked_at: Option<NaiveDateTime>,
+    pub unlock_token_hash: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LockoutStatus {
+    pub user_id: Uuid,
+    pub locked: bool,
+    pub failures: i32,
+    pub last_failure_at: Option<NaiveDateTime>,
+    pub blocked_until: Option<NaiveDateTime>,
+    pub locked_at: Option<NaiveDateTime>,
+}
+
+/// A passwordless sign-in link sent by email.
+/// `token` is the `jti` of the signed link, `state` is bound to the requesting browser.
+#[derive(Debug, Clone, Serialize, Deserialize, Queryable, PartialEq, Insertable)]
+#[table_name = "login_link"]
+pub struct LoginLink {
     pub token: String,
     pub email: String,
+    pub state: String,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub used_at: Option<NaiveDateTime>,
+}
+
+/// A login waiting to be approved from an already authenticated device.
+/// `code` is shown as a QR code, `state` is bound to the browser waiting for approval.
+#[derive(Debug, Clone, Serialize, Deserialize, Queryable, PartialEq, Insertable)]
+#[table_name = "pending_login"]
+pub struct PendingLogin {
+    pub code: String,
+    pub state: String,
+    pub user_id: Option<Uuid>,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub approved_at: Option<NaiveDateTime>,
+    pub claimed_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingLoginResponse {
+    pub code: String,
+    pub approve_url: String,
+    pub expires_at: NaiveDateTime,
+}
+
+/// A previous password hash of a user, kept to refuse its reuse
+#[derive(Debug, Clone, Serialize, Deserialize, Queryable, PartialEq, Insertable)]
+#[table_name = "password_history"]
+pub struct PasswordHistory {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub password: String,
+    pub created_at: NaiveDateTime,
+}
+
+/// A browser the user chose to remember, identified by the signed `trusted_device` cookie.
+#[derive(Debug, Clone, Serialize, Deserialize, Queryable, PartialEq, Insertable)]
+#[table_name = "trusted_device"]
+pub struct TrustedDevice {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub user_agent: String,
+    pub created_at: NaiveDateTime,
+    pub last_seen_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
 }
@@ -1,32 +1,81 @@
 use crate::auth::{
-    decode_access_token, decode_refresh_token, generate_access_token, generate_refresh_token,
-    get_auth_from_header,
+    decode_device_token, decode_login_link_token, decode_refresh_claims, generate_access_token,
+    generate_device_token, generate_login_link_token, generate_refresh_token,
+    get_user_id_from_header, require_step_up, AuthMethod, Authentication, EmailVerificationPolicy,
+};
+use crate::challenge::Challenges;
+use crate::email_templates::{accept_language, EmailTemplates};
+use crate::entity::general::{
+    ChallengeQuery, FieldError, MessageResponse, OutboxEmail, OutboxEmailDTO, OutboxQuery,
+    TokenResponse,
 };
-use crate::entity::general::{MessageResponse, TokenResponse};
 use crate::entity::user::{
-    ForgotRequest, Reset, ResetRequest, UserLoginRequest, UserRegisterationRequest, UserToken,
+    ChangePasswordRequest, EmailVerification, ForgotRequest, LocaleRequest, LockoutStatus,
+    LoginFailure, LoginLink, LoginLinkRequest, PasswordStrengthRequest, PendingLogin,
+    PendingLoginResponse, ReauthenticateRequest, Reset, ResetRequest, TrustedDevice, UserDTO,
+    UserLoginRequest, UserRegisterationRequest, UserToken, VerifyResendRequest,
 };
-use crate::{db::DbPool, entity::user::User};
+use crate::import::{import_users as run_import, ImportFormat};
+use crate::lockout::{account_key, ip_key, LockoutPolicy};
+use crate::mailer::Email;
+use crate::password_policy::PasswordPolicy;
+use crate::utils::{client_ip, hash_token, normalize_email, random_token};
+use crate::{db::DbPool, entity::user::User, hasher::Hasher, MyError};
 use actix_identity::Identity;
 use actix_session::{Session, SessionExt};
 use actix_web::HttpRequest;
 use actix_web::{
-    get, post,
+    delete, get, post,
     web::{self, ServiceConfig},
-    Error, HttpResponse, Responder,
+    HttpResponse, Responder,
 };
 use chrono::Duration;
-use lettre::{ClientSecurity, Message, SmtpTransport, Transport};
 // use cookie::{Cookie, CookieJar};
 use log::{debug, info};
-use rand::distributions::{Alphanumeric, DistString};
+use uuid::Uuid;
 
 pub fn routes_config(config: &mut ServiceConfig) {
     config
+        // malformed bodies and paths are answered like every other error
+        .app_data(web::JsonConfig::default().error_handler(|e, _| {
+            MyError::Validation {
+                desc: e.to_string(),
+            }
+            .into()
+        }))
+        .app_data(web::PathConfig::default().error_handler(|e, _| {
+            MyError::Validation {
+                desc: e.to_string(),
+            }
+            .into()
+        }))
+        // bulk imports are sent as raw bodies of up to 16 MB
+        .app_data(web::PayloadConfig::new(16 * 1024 * 1024))
         .service(health)
+        .service(challenge)
         .service(register)
+        .service(password_strength)
+        .service(verify_email)
+        .service(resend_verification)
         .service(login)
+        .service(unlock_login)
+        .service(request_login_link)
+        .service(login_by_link)
+        .service(create_qr_login)
+        .service(poll_qr_login)
+        .service(approve_qr_login)
+        .service(reauthenticate)
         .service(get_user)
+        .service(update_locale)
+        .service(list_trusted_devices)
+        .service(revoke_trusted_device)
+        .service(change_password)
+        .service(import_users)
+        .service(get_lockout)
+        .service(clear_lockout)
+        .service(list_outbox)
+        .service(replay_outbox_email)
+        .service(replay_outbox)
         .service(refresh)
         .service(logout)
         .service(forgot)
@@ -52,255 +101,1247 @@ pub async fn health() -> impl Responder {
     HttpResponse::Ok().body("Healthy")
 }
 
+#[get("/metrics")]
+/// Password hashing pool metrics in the Prometheus text format,
+/// only served on the separate `METRICS_ADDRESS` listener.
+pub async fn metrics(hasher: web::Data<Hasher>) -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(hasher.render_metrics())
+}
+
+#[get("/challenge")]
+/// Issue a proof-of-work challenge for one of the routes listed in `POW_ROUTES`.
+pub async fn challenge(
+    query: web::Query<ChallengeQuery>,
+    challenges: web::Data<Challenges>,
+) -> Result<HttpResponse, MyError> {
+    let response = challenges.issue(&query.route)?;
+    Ok(HttpResponse::Ok().json(response))
+}
+
 #[post("/register")]
 pub async fn register(
     data: web::Json<UserRegisterationRequest>,
+    request: HttpRequest,
+    hasher: web::Data<Hasher>,
+    templates: web::Data<EmailTemplates>,
     pool: web::Data<DbPool>,
-) -> Result<HttpResponse, Error> {
+) -> Result<HttpResponse, MyError> {
     debug!("{:?}", data.0.clone());
+    let mut data = data.into_inner();
+    if let Some(locale) = data.locale {
+        data.locale = Some(supported_locale(&locale, &templates)?);
+    }
+
+    let user_inputs = vec![
+        data.email.clone(),
+        data.first_name.clone(),
+        data.last_name.clone(),
+    ];
+    PasswordPolicy::from_env()
+        .validate(data.password.clone(), &data.password_confirm, user_inputs)
+        .await?;
 
-    if data.0.password != data.0.password_confirm {
-        return Ok(HttpResponse::BadRequest().json("Kindly confirm the same password."));
+    // Same response whether the email is registered or not, its owner is told by email instead
+    let email = data.email.clone();
+    let locale = templates.locale_for(data.locale.as_deref(), accept_language(&request));
+    let verification = {
+        let templates = templates.clone();
+        move |user: &UserDTO| verification_email(user, &locale, &templates)
+    };
+    match User::insert(data, verification, &hasher, &pool).await {
+        Ok(_) => {}
+        Err(MyError::Conflict { .. }) => {
+            if let Ok(user) = User::find_by_email(email, &pool).await {
+                let locale =
+                    templates.locale_for(user.locale.as_deref(), accept_language(&request));
+                queue_email(
+                    already_registered_email(&user.as_dto(), &locale, &templates),
+                    &pool,
+                )
+                .await;
+            }
+        }
+        Err(e) => return Err(e),
     }
 
-    match User::insert(data.0, &pool).await {
-        Ok(feedback) => Ok(HttpResponse::Ok().json(feedback)),
-        Err(e) => Ok(HttpResponse::BadRequest().json(e.to_string())),
+    let response = MessageResponse {
+        message: "success".to_string(),
+    };
+    Ok(HttpResponse::Ok().json(response))
+}
+
+#[post("/password/strength")]
+/// Score a candidate password for the frontend meter, with the violations of the password policy.
+pub async fn password_strength(
+    data: web::Json<PasswordStrengthRequest>,
+) -> Result<HttpResponse, MyError> {
+    let data = data.into_inner();
+    let user_inputs = [data.email, data.first_name, data.last_name]
+        .into_iter()
+        .flatten()
+        .collect();
+
+    let response = PasswordPolicy::from_env()
+        .evaluate(data.password, user_inputs)
+        .await?;
+    Ok(HttpResponse::Ok().json(response))
+}
+
+/// Locale of the email templates matching `locale`, which users can choose
+fn supported_locale(locale: &str, templates: &EmailTemplates) -> Result<String, MyError> {
+    templates
+        .negotiate(locale)
+        .map(str::to_string)
+        .ok_or(MyError::InvalidFields {
+            errors: vec![FieldError {
+                field: "locale".to_string(),
+                code: "unsupported".to_string(),
+                message: format!("Emails are not available in {locale}"),
+            }],
+        })
+}
+
+/// Queue an email which goes with no other change.
+/// Failures are only logged, so that responses do not tell whether an email was queued.
+async fn queue_email(email: Result<Email, MyError>, pool: &DbPool) {
+    let result = match email {
+        Ok(email) => OutboxEmail::insert(email, pool).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        info!("queue_email: {}", e);
     }
 }
 
+/// Verification of `user.email`, with the email holding its link.
+/// Only the hash of the token is stored, the link works once, for a day.
+fn verification_email(
+    user: &UserDTO,
+    locale: &str,
+    templates: &EmailTemplates,
+) -> Result<(EmailVerification, Email), MyError> {
+    let token = random_token(32);
+    let now = chrono::Utc::now().naive_utc();
+    let verification = EmailVerification {
+        token_hash: hash_token(&token),
+        user_id: user.id,
+        email: normalize_email(&user.email),
+        created_at: now,
+        expires_at: now + Duration::days(1),
+        used_at: None,
+    };
+
+    let frontend_url =
+        std::env::var("FRONTEND_URL").expect("Missed 'FRONTEND_URL' environment variable");
+    let variables = serde_json::json!({
+        "first_name": user.first_name,
+        "verify_url": format!("{frontend_url}/verify/{token}"),
+    });
+    let email = templates.render("verification", locale, &user.email, variables)?;
+    Ok((verification, email))
+}
+
+/// Email telling the owner of `user.email` that someone tried to register with it
+fn already_registered_email(
+    user: &UserDTO,
+    locale: &str,
+    templates: &EmailTemplates,
+) -> Result<Email, MyError> {
+    let frontend_url =
+        std::env::var("FRONTEND_URL").expect("Missed 'FRONTEND_URL' environment variable");
+
+    let variables = serde_json::json!({
+        "first_name": user.first_name,
+        "login_url": format!("{frontend_url}/login"),
+        "forgot_url": format!("{frontend_url}/forgot"),
+    });
+    templates.render("already_registered", locale, &user.email, variables)
+}
+
+#[get("/verify/{token}")]
+pub async fn verify_email(
+    path: web::Path<String>,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, MyError> {
+    EmailVerification::verify(hash_token(&path.into_inner()), &pool).await?;
+
+    let response = MessageResponse {
+        message: "success".to_string(),
+    };
+    Ok(HttpResponse::Ok().json(response))
+}
+
+#[post("/verify/resend")]
+pub async fn resend_verification(
+    data: web::Json<VerifyResendRequest>,
+    request: HttpRequest,
+    templates: web::Data<EmailTemplates>,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, MyError> {
+    // Same response whether the email is registered, verified or not
+    if let Ok(user) = User::find_by_email(data.0.email, &pool).await {
+        if user.email_verified_at.is_none() {
+            let locale = templates.locale_for(user.locale.as_deref(), accept_language(&request));
+            let result = match verification_email(&user.as_dto(), &locale, &templates) {
+                Ok((verification, email)) => {
+                    EmailVerification::insert(verification, email, &pool).await
+                }
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                info!("/verify/resend -> EmailVerification::insert: {}", e);
+            }
+        }
+    }
+
+    let response = MessageResponse {
+        message: "success".to_string(),
+    };
+    Ok(HttpResponse::Ok().json(response))
+}
+
+/// Scope of a new session of `user`, following the `EMAIL_VERIFICATION` policy
+fn session_scope(user: &UserDTO) -> Result<&'static str, MyError> {
+    let policy = EmailVerificationPolicy::from_env();
+    if policy == EmailVerificationPolicy::Block && !user.email_verified {
+        return Err(MyError::EmailNotVerified);
+    }
+
+    Ok(policy.scope(user.email_verified))
+}
+
+/// Issue an access token and a persisted refresh token for `user`.
+/// The refresh token is set as the `refresh_token` cookie.
+async fn issue_session(
+    user: &UserDTO,
+    auth: Authentication,
+    pool: &DbPool,
+) -> Result<HttpResponse, MyError> {
+    let user_id = user.id;
+    let scope = session_scope(user)?;
+    let access_token = generate_access_token(user_id, &auth, scope);
+    let refresh_token = generate_refresh_token(user_id, &auth, scope);
+
+    info!("access_token {}", access_token);
+    info!("refresh_token {}", refresh_token);
+
+    let now = chrono::Utc::now();
+
+    let user_token = UserToken {
+        user_id,
+        token: refresh_token.clone(),
+        created_at: now.naive_utc(),
+        expires_at: now.naive_utc() + Duration::days(7),
+    };
+
+    UserToken::insert(user_token, pool).await?;
+
+    let cookie = actix_web::cookie::Cookie::build("refresh_token", refresh_token)
+        .http_only(true)
+        .finish();
+
+    let response = TokenResponse {
+        token: access_token,
+    };
+
+    Ok(HttpResponse::Ok().cookie(cookie).json(response))
+}
+
+/// Whether the request comes from a device the user chose to remember.
+/// Extra checks on new devices can be skipped for trusted ones.
+pub async fn is_trusted_device(request: &HttpRequest, user_id: Uuid, pool: &DbPool) -> bool {
+    let cookie = match request.cookie("trusted_device") {
+        Some(cookie) => cookie,
+        None => return false,
+    };
+
+    match decode_device_token(cookie.value().to_string()) {
+        Ok(claims) if claims.user_id == user_id.to_string() => {
+            match Uuid::parse_str(&claims.device_id) {
+                Ok(device_id) => TrustedDevice::touch(device_id, user_id, pool).await.is_ok(),
+                Err(_) => false,
+            }
+        }
+        _ => false,
+    }
+}
+
+/// Authenticate an administrator: a recently signed-in user whose email is listed in the
+/// comma-separated `ADMIN_EMAILS` environment variable.
+async fn require_admin(request: HttpRequest, pool: &DbPool) -> Result<Uuid, MyError> {
+    let user_id = require_step_up(request)?;
+    let user = User::find_by_id(user_id, pool).await?;
+
+    let admins = std::env::var("ADMIN_EMAILS").unwrap_or_default();
+    let email = normalize_email(&user.email);
+    if admins
+        .split(',')
+        .any(|admin| normalize_email(admin) == email)
+    {
+        Ok(user_id)
+    } else {
+        Err(MyError::Forbidden {
+            desc: "Administrators only".to_string(),
+        })
+    }
+}
+
+/// Remember the requesting browser for 30 days and return the `trusted_device` cookie
+async fn remember_device(
+    request: &HttpRequest,
+    user_id: Uuid,
+    pool: &DbPool,
+) -> Result<actix_web::cookie::Cookie<'static>, MyError> {
+    let user_agent = request
+        .headers()
+        .get(actix_web::http::header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+
+    let now = chrono::Utc::now().naive_utc();
+    let device = TrustedDevice {
+        id: Uuid::new_v4(),
+        user_id,
+        user_agent,
+        created_at: now,
+        last_seen_at: now,
+        expires_at: now + Duration::days(30),
+    };
+    let device = TrustedDevice::insert(device, pool).await?;
+
+    let token = generate_device_token(user_id, device.id, Duration::days(30));
+    Ok(actix_web::cookie::Cookie::build("trusted_device", token)
+        .http_only(true)
+        .max_age(actix_web::cookie::time::Duration::days(30))
+        .finish())
+}
+
 #[post("/login")]
 pub async fn login(
     data: web::Json<UserLoginRequest>,
+    request: HttpRequest,
+    hasher: web::Data<Hasher>,
+    templates: web::Data<EmailTemplates>,
     pool: web::Data<DbPool>,
-) -> Result<HttpResponse, Error> {
-    match User::authenticate_by_email(data.0.email, data.0.password, &pool).await {
-        Ok(user) => {
-            let user_id = user.id;
-            let access_token = generate_access_token(user_id);
-            let refresh_token = generate_refresh_token(user_id);
-
-            info!("access_token {}", access_token);
-            info!("refresh_token {}", refresh_token);
-
-            let now = chrono::Utc::now();
-
-            let user_token = UserToken {
-                user_id: user_id,
-                token: refresh_token.clone(),
-                created_at: now.naive_utc(),
-                expires_at: now.naive_utc() + Duration::days(7),
-            };
+) -> Result<HttpResponse, MyError> {
+    let remember = data.0.remember_device;
+
+    // Counted per email, registered or not, so a lockout does not tell whether an account exists
+    let account_key = account_key(&data.0.email);
+    let ip_key = ip_key(&request);
+    LoginFailure::check(vec![account_key.clone(), ip_key.clone()], &pool).await?;
 
-            match UserToken::insert(user_token, &pool).await {
-                Ok(_) => {
-                    let cookie =
-                        actix_web::cookie::Cookie::build("refresh_token", refresh_token.clone())
-                            .http_only(true)
-                            .finish();
+    let email = data.0.email.clone();
+    let user =
+        match User::authenticate_by_email(data.0.email, data.0.password, &hasher, &pool).await {
+            Ok(user) => user,
+            Err(e @ MyError::Unauthenticated { .. }) => {
+                record_failed_login(email, account_key, ip_key, &request, &templates, &pool).await;
+                return Err(e);
+            }
+            Err(e) => return Err(e),
+        };
+    LoginFailure::clear(account_key, &pool).await?;
+
+    let trusted = is_trusted_device(&request, user.id, &pool).await;
+    info!("/login -> trusted_device: {}", trusted);
+    if !trusted {
+        let locale = templates.locale_for(user.locale.as_deref(), accept_language(&request));
+        queue_email(
+            new_sign_in_email(&user, &request, &locale, &templates),
+            &pool,
+        )
+        .await;
+    }
 
-                    let response = TokenResponse {
-                        token: access_token,
-                    };
+    let auth = Authentication::new(AuthMethod::Password);
+    let mut http_response = issue_session(&user.as_dto(), auth, &pool).await?;
+    if remember && !trusted {
+        match remember_device(&request, user.id, &pool).await {
+            Ok(cookie) => http_response.add_cookie(&cookie).unwrap(),
+            Err(e) => info!("/login -> remember_device: {}", e),
+        }
+    }
+
+    Ok(http_response)
+}
+
+/// Count a failed login, or a wrong password confirmation of a signed-in user, against the
+/// account and the client.
+/// The owner of a newly locked account is emailed an unlock link, failures are only logged.
+async fn record_failed_login(
+    email: String,
+    account_key: String,
+    ip_key: String,
+    request: &HttpRequest,
+    templates: &EmailTemplates,
+    pool: &DbPool,
+) {
+    let policy = LockoutPolicy::from_env();
+    if let Err(e) = LoginFailure::record(ip_key, policy.ip, policy.clone(), pool).await {
+        info!("LoginFailure::record: {}", e);
+    }
 
-                    Ok(HttpResponse::Ok().cookie(cookie).json(response))
+    match LoginFailure::record(account_key.clone(), policy.account, policy, pool).await {
+        Ok((_, true)) => {
+            if let Ok(user) = User::find_by_email(email, pool).await {
+                let token = random_token(32);
+                let locale = templates.locale_for(user.locale.as_deref(), accept_language(request));
+                let result = match unlock_email(&user, &token, &locale, templates) {
+                    Ok(unlock_email) => {
+                        let token_hash = hash_token(&token);
+                        LoginFailure::set_unlock_token(account_key, token_hash, unlock_email, pool)
+                            .await
+                    }
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = result {
+                    info!("LoginFailure::set_unlock_token: {}", e);
                 }
-                Err(e) => Ok(HttpResponse::BadRequest().json(e.to_string())),
             }
         }
-        Err(e) => Ok(HttpResponse::Unauthorized().json(e.to_string())),
+        Ok(_) => {}
+        Err(e) => info!("LoginFailure::record: {}", e),
+    }
+}
+
+/// Email telling the owner of a locked account, with a link lifting the lockout
+fn unlock_email(
+    user: &User,
+    token: &str,
+    locale: &str,
+    templates: &EmailTemplates,
+) -> Result<Email, MyError> {
+    let frontend_url =
+        std::env::var("FRONTEND_URL").expect("Missed 'FRONTEND_URL' environment variable");
+
+    let variables = serde_json::json!({
+        "first_name": user.first_name,
+        "unlock_url": format!("{frontend_url}/login/unlock/{token}"),
+    });
+    templates.render("unlock", locale, &user.email, variables)
+}
+
+/// Email telling a user about a sign-in from a device which is not remembered
+fn new_sign_in_email(
+    user: &User,
+    request: &HttpRequest,
+    locale: &str,
+    templates: &EmailTemplates,
+) -> Result<Email, MyError> {
+    let frontend_url =
+        std::env::var("FRONTEND_URL").expect("Missed 'FRONTEND_URL' environment variable");
+    let user_agent = request
+        .headers()
+        .get(actix_web::http::header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("unknown");
+    let ip_address = client_ip(request).map_or("unknown".to_string(), |ip| ip.to_string());
+
+    let variables = serde_json::json!({
+        "first_name": user.first_name,
+        "user_agent": user_agent,
+        "ip_address": ip_address,
+        "signed_in_at": chrono::Utc::now().format("%Y-%m-%d %H:%M").to_string(),
+        "forgot_url": format!("{frontend_url}/forgot"),
+    });
+    templates.render("new_sign_in", locale, &user.email, variables)
+}
+
+#[get("/login/unlock/{token}")]
+/// Lift the lockout of an account from the link emailed to its owner.
+pub async fn unlock_login(
+    path: web::Path<String>,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, MyError> {
+    LoginFailure::unlock(hash_token(&path.into_inner()), &pool).await?;
+
+    let response = MessageResponse {
+        message: "success".to_string(),
+    };
+    Ok(HttpResponse::Ok().json(response))
+}
+
+#[post("/login/link")]
+/// Email a single-use sign-in link.
+/// The link only works in the browser that requested it, through the `login_link_state` cookie.
+pub async fn request_login_link(
+    data: web::Json<LoginLinkRequest>,
+    request: HttpRequest,
+    templates: web::Data<EmailTemplates>,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, MyError> {
+    let email = data.0.email;
+    let state = random_token(32);
+    info!("/login/link -> email: {}", &email);
+
+    // Same response whether the email is registered or not
+    let response = MessageResponse {
+        message: "success".to_string(),
+    };
+    let state_cookie = actix_web::cookie::Cookie::build("login_link_state", state.clone())
+        .http_only(true)
+        .max_age(actix_web::cookie::time::Duration::minutes(15))
+        .finish();
+
+    if let Ok(user) = User::find_by_email(email.clone(), &pool).await {
+        let now = chrono::Utc::now().naive_utc();
+        let link = LoginLink {
+            token: random_token(32),
+            email: user.email.clone(),
+            state,
+            created_at: now,
+            expires_at: now + Duration::minutes(15),
+            used_at: None,
+        };
+
+        let frontend_url =
+            std::env::var("FRONTEND_URL").expect("Missed 'FRONTEND_URL' environment variable");
+        let token = generate_login_link_token(
+            link.email.clone(),
+            link.token.clone(),
+            Duration::minutes(15),
+        );
+
+        let variables = serde_json::json!({
+            "first_name": user.first_name,
+            "login_url": format!("{frontend_url}/login/link/{token}"),
+        });
+        let locale = templates.locale_for(user.locale.as_deref(), accept_language(&request));
+        // Failures are only logged, an error would tell the email is registered
+        let result = match templates.render("login_link", &locale, &link.email, variables) {
+            Ok(link_email) => LoginLink::insert(link, link_email, &pool).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            info!("/login/link -> LoginLink::insert: {}", e);
+        }
+    }
+
+    Ok(HttpResponse::Ok().cookie(state_cookie).json(response))
+}
+
+#[get("/login/link/{token}")]
+/// Complete a passwordless login, setting the same `refresh_token` cookie as `login`.
+pub async fn login_by_link(
+    path: web::Path<String>,
+    request: HttpRequest,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, MyError> {
+    let state_cookie = request
+        .cookie("login_link_state")
+        .ok_or(MyError::Unauthenticated {
+            desc: "No `login_link_state` found".to_string(),
+        })?;
+
+    let claims = decode_login_link_token(path.into_inner())?;
+    let link = LoginLink::consume(claims.jti, state_cookie.value().to_string(), &pool).await?;
+    if link.email != claims.email {
+        return Err(MyError::Unauthenticated {
+            desc: "Invalid link".to_string(),
+        });
+    }
+
+    // Opening the link proves the ownership of the email
+    let mut user = User::find_by_email(link.email, &pool).await?.as_dto();
+    if !user.email_verified {
+        match User::verify_email(user.id, user.email.clone(), &pool).await {
+            Ok(_) => user.email_verified = true,
+            Err(e) => info!("/login/link -> verify_email: {}", e),
+        }
+    }
+
+    let auth = Authentication::new(AuthMethod::EmailLink);
+    let mut http_response = issue_session(&user, auth, &pool).await?;
+    http_response.add_removal_cookie(&state_cookie).unwrap();
+    Ok(http_response)
+}
+
+#[post("/login/qr")]
+/// Start a cross-device login, the returned `approve_url` is shown as a QR code.
+/// This browser then polls `/login/qr/{code}` until the login is approved from a signed-in device.
+pub async fn create_qr_login(pool: web::Data<DbPool>) -> Result<HttpResponse, MyError> {
+    let now = chrono::Utc::now().naive_utc();
+    let pending = PendingLogin {
+        code: random_token(32),
+        state: random_token(32),
+        user_id: None,
+        created_at: now,
+        expires_at: now + Duration::minutes(5),
+        approved_at: None,
+        claimed_at: None,
+    };
+
+    let pending = PendingLogin::insert(pending, &pool).await?;
+
+    let frontend_url =
+        std::env::var("FRONTEND_URL").expect("Missed 'FRONTEND_URL' environment variable");
+
+    let state_cookie = actix_web::cookie::Cookie::build("qr_login_state", pending.state)
+        .http_only(true)
+        .max_age(actix_web::cookie::time::Duration::minutes(5))
+        .finish();
+
+    let response = PendingLoginResponse {
+        approve_url: format!("{frontend_url}/login/qr/{}", &pending.code),
+        code: pending.code,
+        expires_at: pending.expires_at,
+    };
+    Ok(HttpResponse::Ok().cookie(state_cookie).json(response))
+}
+
+#[get("/login/qr/{code}")]
+/// Polled by the browser which created the pending login.
+/// Answers `202 Accepted` while waiting, and starts a session once approved.
+pub async fn poll_qr_login(
+    path: web::Path<String>,
+    request: HttpRequest,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, MyError> {
+    let code = path.into_inner();
+    let state_cookie = request
+        .cookie("qr_login_state")
+        .ok_or(MyError::Unauthenticated {
+            desc: "No `qr_login_state` found".to_string(),
+        })?;
+    let state = state_cookie.value().to_string();
+
+    let pending = PendingLogin::find_by_code(code.clone(), state.clone(), &pool).await?;
+    if pending.claimed_at.is_some() {
+        return Err(MyError::Gone {
+            desc: "Code already used".to_string(),
+        });
+    }
+
+    if pending.approved_at.is_none() {
+        if pending.expires_at < chrono::Utc::now().naive_utc() {
+            return Err(MyError::Gone {
+                desc: "Code expired".to_string(),
+            });
+        }
+
+        let response = MessageResponse {
+            message: "pending".to_string(),
+        };
+        return Ok(HttpResponse::Accepted().json(response));
     }
+
+    let user_id = PendingLogin::claim(code, state, &pool)
+        .await?
+        .user_id
+        .ok_or(MyError::Unauthenticated {
+            desc: "Invalid code".to_string(),
+        })?;
+    let user = User::find_by_id(user_id, &pool).await?;
+
+    let auth = Authentication::new(AuthMethod::CrossDevice);
+    let mut http_response = issue_session(&user, auth, &pool).await?;
+    http_response.add_removal_cookie(&state_cookie).unwrap();
+    Ok(http_response)
+}
+
+#[post("/login/qr/{code}/approve")]
+/// Approve a pending login from a device where the user is already signed in.
+pub async fn approve_qr_login(
+    path: web::Path<String>,
+    request: HttpRequest,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, MyError> {
+    // Approving hands out a new session, so it needs a recent sign-in on this device
+    let user_id = require_step_up(request)?;
+    PendingLogin::approve(path.into_inner(), user_id, &pool).await?;
+
+    let response = MessageResponse {
+        message: "success".to_string(),
+    };
+    Ok(HttpResponse::Ok().json(response))
+}
+
+#[post("/reauthenticate")]
+/// Confirm the password of the signed-in user to refresh `auth_time`, `amr` and `acr`.
+/// Replaces the current session with a new one allowed to perform sensitive operations.
+pub async fn reauthenticate(
+    data: web::Json<ReauthenticateRequest>,
+    request: HttpRequest,
+    hasher: web::Data<Hasher>,
+    templates: web::Data<EmailTemplates>,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, MyError> {
+    let user_id = get_user_id_from_header(request.clone())?;
+    let user = User::find_by_id(user_id, &pool).await?;
+
+    // Password guesses count toward the same lockout as failed logins
+    let account_key = account_key(&user.email);
+    let ip_key = ip_key(&request);
+    LoginFailure::check(vec![account_key.clone(), ip_key.clone()], &pool).await?;
+
+    let email = user.email.clone();
+    let user = match User::authenticate_by_email(user.email, data.0.password, &hasher, &pool).await
+    {
+        Ok(user) => user,
+        Err(e @ MyError::Unauthenticated { .. }) => {
+            record_failed_login(email, account_key, ip_key, &request, &templates, &pool).await;
+            return Err(e);
+        }
+        Err(e) => return Err(e),
+    };
+    LoginFailure::clear(account_key, &pool).await?;
+
+    let auth = Authentication::new(AuthMethod::Password);
+    let http_response = issue_session(&user.as_dto(), auth, &pool).await?;
+
+    // the previous refresh token is replaced by the new one
+    if let Some(cookie) = request.cookie("refresh_token") {
+        if let Err(e) = UserToken::delete(cookie.value().to_string(), &pool).await {
+            info!("/reauthenticate -> UserToken::delete: {}", e);
+        }
+    }
+
+    Ok(http_response)
 }
 
 #[get("/user")]
 pub async fn get_user(
     request: HttpRequest,
     pool: web::Data<DbPool>,
-) -> Result<HttpResponse, Error> {
-    match get_auth_from_header(request) {
-        Ok(auth_token) => {
-            dbg!(auth_token.clone());
-
-            match decode_access_token(auth_token) {
-                Ok(user_id) => {
-                    let id = uuid::Uuid::parse_str(&user_id).unwrap();
-                    match User::find_by_id(id, &pool).await {
-                        Ok(user) => Ok(HttpResponse::Ok().json(user)),
-                        Err(e) => Ok(HttpResponse::NotFound().json(e.to_string())),
-                    }
-                }
-                Err(e) => Ok(HttpResponse::Unauthorized().json(e.to_string())),
-            }
+) -> Result<HttpResponse, MyError> {
+    let id = get_user_id_from_header(request)?;
+    let user = User::find_by_id(id, &pool).await?;
+
+    Ok(HttpResponse::Ok().json(user))
+}
+
+#[post("/user/locale")]
+/// Choose the language of the emails sent to the authenticated user
+pub async fn update_locale(
+    data: web::Json<LocaleRequest>,
+    request: HttpRequest,
+    templates: web::Data<EmailTemplates>,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, MyError> {
+    let user_id = get_user_id_from_header(request)?;
+    let locale = supported_locale(&data.0.locale, &templates)?;
+    let user = User::update_locale(user_id, locale, &pool).await?;
+
+    Ok(HttpResponse::Ok().json(user))
+}
+
+#[get("/user/devices")]
+/// List the devices remembered for the authenticated user
+pub async fn list_trusted_devices(
+    request: HttpRequest,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, MyError> {
+    let user_id = get_user_id_from_header(request)?;
+    let devices = TrustedDevice::find_by_user(user_id, &pool).await?;
+
+    Ok(HttpResponse::Ok().json(devices))
+}
+
+#[delete("/user/devices/{id}")]
+/// Forget one remembered device of the authenticated user
+pub async fn revoke_trusted_device(
+    path: web::Path<Uuid>,
+    request: HttpRequest,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, MyError> {
+    let user_id = get_user_id_from_header(request)?;
+    TrustedDevice::delete(path.into_inner(), user_id, &pool).await?;
+
+    let response = MessageResponse {
+        message: "success".to_string(),
+    };
+    Ok(HttpResponse::Ok().json(response))
+}
+
+#[post("/user/password")]
+/// Change the password of the authenticated user, who must have signed in recently and confirm
+/// the current one. Every other session and remembered device is signed out, the current session
+/// is kept.
+pub async fn change_password(
+    data: web::Json<ChangePasswordRequest>,
+    request: HttpRequest,
+    hasher: web::Data<Hasher>,
+    templates: web::Data<EmailTemplates>,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, MyError> {
+    let user_id = require_step_up(request.clone())?;
+    let user = User::find_by_id(user_id, &pool).await?;
+    let data = data.into_inner();
+
+    let account_key = account_key(&user.email);
+    let ip_key = ip_key(&request);
+    LoginFailure::check(vec![account_key.clone(), ip_key.clone()], &pool).await?;
+
+    match User::authenticate_by_email(user.email.clone(), data.current_password, &hasher, &pool)
+        .await
+    {
+        Ok(_) => {}
+        Err(MyError::Unauthenticated { .. }) => {
+            let email = user.email.clone();
+            record_failed_login(email, account_key, ip_key, &request, &templates, &pool).await;
+            return Err(MyError::InvalidFields {
+                errors: vec![FieldError {
+                    field: "current_password".to_string(),
+                    code: "incorrect".to_string(),
+                    message: "The current password is incorrect".to_string(),
+                }],
+            });
         }
-        Err(e) => Ok(HttpResponse::Unauthorized().json(e.to_string())),
+        Err(e) => return Err(e),
     }
+    LoginFailure::clear(account_key, &pool).await?;
+
+    let user_inputs = vec![
+        user.email.clone(),
+        user.first_name.clone(),
+        user.last_name.clone(),
+    ];
+    PasswordPolicy::from_env()
+        .validate(data.password.clone(), &data.password_confirm, user_inputs)
+        .await?;
+
+    let frontend_url =
+        std::env::var("FRONTEND_URL").expect("Missed 'FRONTEND_URL' environment variable");
+    let variables = serde_json::json!({
+        "first_name": user.first_name,
+        "forgot_url": format!("{frontend_url}/forgot"),
+    });
+    let locale = templates.locale_for(user.locale.as_deref(), accept_language(&request));
+    let notification = templates.render("password_changed", &locale, &user.email, variables)?;
+    let current_token = request
+        .cookie("refresh_token")
+        .map(|cookie| cookie.value().to_string());
+    let revoked = User::update_password(
+        user_id,
+        data.password,
+        current_token,
+        notification,
+        &hasher,
+        &pool,
+    )
+    .await?;
+    info!("/user/password -> {} other sessions revoked", revoked);
+
+    let response = MessageResponse {
+        message: "success".to_string(),
+    };
+    Ok(HttpResponse::Ok().json(response))
+}
+
+#[post("/admin/users/import")]
+/// Bulk import users with password hashes from another application.
+/// The body is CSV (`text/csv`) or JSON Lines (`application/x-ndjson`), failing rows are reported.
+pub async fn import_users(
+    body: web::Bytes,
+    request: HttpRequest,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, MyError> {
+    let format = request
+        .headers()
+        .get(actix_web::http::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(ImportFormat::from_content_type)
+        .ok_or(MyError::Validation {
+            desc: "Send text/csv or application/x-ndjson".to_string(),
+        })?;
+    let admin_id = require_admin(request, &pool).await?;
+
+    let report = run_import(format, &body, &pool).await;
+    info!(
+        "/admin/users/import by {}: {} imported, {} failed",
+        admin_id, report.imported, report.failed
+    );
+
+    Ok(HttpResponse::Ok().json(report))
+}
+
+#[get("/admin/users/{id}/lockout")]
+/// Failed logins and lockout of a user's account.
+pub async fn get_lockout(
+    path: web::Path<Uuid>,
+    request: HttpRequest,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, MyError> {
+    require_admin(request, &pool).await?;
+    let user = User::find_by_id(path.into_inner(), &pool).await?;
+
+    let now = chrono::Utc::now().naive_utc();
+    let failure = LoginFailure::find(account_key(&user.email), &pool).await?;
+    let status = match failure {
+        Some(failure) => LockoutStatus {
+            user_id: user.id,
+            locked: failure.blocked_until.is_some_and(|until| until > now),
+            failures: failure.failures,
+            last_failure_at: Some(failure.last_failure_at),
+            blocked_until: failure.blocked_until,
+            locked_at: failure.locked_at,
+        },
+        None => LockoutStatus {
+            user_id: user.id,
+            locked: false,
+            failures: 0,
+            last_failure_at: None,
+            blocked_until: None,
+            locked_at: None,
+        },
+    };
+
+    Ok(HttpResponse::Ok().json(status))
+}
+
+#[delete("/admin/users/{id}/lockout")]
+/// Forget the failed logins of a user's account, lifting its lockout.
+pub async fn clear_lockout(
+    path: web::Path<Uuid>,
+    request: HttpRequest,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, MyError> {
+    let admin_id = require_admin(request, &pool).await?;
+    let user = User::find_by_id(path.into_inner(), &pool).await?;
+
+    LoginFailure::clear(account_key(&user.email), &pool).await?;
+    info!("/admin/users/{}/lockout cleared by {}", user.id, admin_id);
+
+    let response = MessageResponse {
+        message: "success".to_string(),
+    };
+    Ok(HttpResponse::Ok().json(response))
+}
+
+#[get("/admin/outbox")]
+/// The latest 100 emails of the outbox, optionally only those with a given `status`.
+pub async fn list_outbox(
+    query: web::Query<OutboxQuery>,
+    request: HttpRequest,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, MyError> {
+    require_admin(request, &pool).await?;
+
+    let emails = OutboxEmail::find(query.into_inner().status, 100, &pool).await?;
+    let emails: Vec<OutboxEmailDTO> = emails.iter().map(OutboxEmail::as_dto).collect();
+    Ok(HttpResponse::Ok().json(emails))
+}
+
+#[post("/admin/outbox/{id}/replay")]
+/// Queue a dead email again, with a fresh count of attempts.
+pub async fn replay_outbox_email(
+    path: web::Path<Uuid>,
+    request: HttpRequest,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, MyError> {
+    let admin_id = require_admin(request, &pool).await?;
+    let id = path.into_inner();
+
+    OutboxEmail::replay(Some(id), &pool).await?;
+    info!("/admin/outbox/{}/replay by {}", id, admin_id);
+
+    let response = MessageResponse {
+        message: "success".to_string(),
+    };
+    Ok(HttpResponse::Ok().json(response))
+}
+
+#[post("/admin/outbox/replay")]
+/// Queue every dead email again, with a fresh count of attempts.
+pub async fn replay_outbox(
+    request: HttpRequest,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, MyError> {
+    let admin_id = require_admin(request, &pool).await?;
+
+    let replayed = OutboxEmail::replay(None, &pool).await?;
+    info!(
+        "/admin/outbox/replay -> {} emails by {}",
+        replayed, admin_id
+    );
+
+    let response = MessageResponse {
+        message: format!("{replayed} emails queued again"),
+    };
+    Ok(HttpResponse::Ok().json(response))
 }
 
 #[get("/refresh")]
-pub async fn refresh(request: HttpRequest, pool: web::Data<DbPool>) -> Result<HttpResponse, Error> {
-    match request.cookie("refresh_token") {
-        Some(cookie) => {
-            let refresh_token = cookie.value();
-            dbg!(refresh_token.clone());
-            match decode_refresh_token(refresh_token.to_string()) {
-                Ok(user_id) => {
-                    let id = uuid::Uuid::parse_str(&user_id).unwrap();
-                    match User::find_by_id(id, &pool).await {
-                        Ok(user) => {
-                            // if token for this user not exist, return unauthenticated error
-                            match UserToken::find_by_token(refresh_token.to_string(), id, &pool)
-                                .await
-                            {
-                                Ok(user_token) => {
-                                    let now = chrono::Utc::now().naive_utc();
-                                    if user_token.expires_at < now {
-                                        return Ok(
-                                            HttpResponse::Unauthorized().json("Token expired")
-                                        );
-                                    }
-
-                                    let access_token = generate_access_token(user.id);
-                                    let response = TokenResponse {
-                                        token: access_token,
-                                    };
-
-                                    Ok(HttpResponse::Ok().json(response))
-                                }
-                                Err(_) => Ok(HttpResponse::Unauthorized().json("Token not found")),
-                            }
-                        }
-                        Err(e) => Ok(HttpResponse::NotFound().json(e.to_string())),
-                    }
-                }
-                Err(e) => Ok(HttpResponse::Unauthorized().json(e.to_string())),
-            }
-        }
-        None => Ok(HttpResponse::Unauthorized().json("No `refresh_token` found")),
+pub async fn refresh(
+    request: HttpRequest,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, MyError> {
+    let cookie = request
+        .cookie("refresh_token")
+        .ok_or(MyError::Unauthenticated {
+            desc: "No `refresh_token` found".to_string(),
+        })?;
+    let refresh_token = cookie.value();
+
+    let claims = decode_refresh_claims(refresh_token.to_string())?;
+    let id = Uuid::parse_str(&claims.user_id).map_err(|_| MyError::Unauthenticated {
+        desc: "Invalid token".to_string(),
+    })?;
+    let user = User::find_by_id(id, &pool).await?;
+
+    // if token for this user not exist, return unauthenticated error
+    let user_token = UserToken::find_by_token(refresh_token.to_string(), id, &pool)
+        .await
+        .map_err(|_| MyError::Unauthenticated {
+            desc: "Token not found".to_string(),
+        })?;
+
+    let now = chrono::Utc::now().naive_utc();
+    if user_token.expires_at < now {
+        return Err(MyError::Unauthenticated {
+            desc: "Token expired".to_string(),
+        });
     }
+
+    let scope = session_scope(&user)?;
+    let access_token = generate_access_token(user.id, &claims.auth, scope);
+    let response = TokenResponse {
+        token: access_token,
+    };
+
+    Ok(HttpResponse::Ok().json(response))
 }
 
 #[get("/logout")]
-pub async fn logout(request: HttpRequest, pool: web::Data<DbPool>) -> Result<HttpResponse, Error> {
+pub async fn logout(
+    request: HttpRequest,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, MyError> {
     let response = MessageResponse {
-        message: format!("success"),
+        message: "success".to_string(),
     };
 
     let mut http_response = HttpResponse::Ok().json(response);
     // let cookie_to_remove = request.cookie("refresh_token").unwrap();
-    match request.cookie("refresh_token") {
-        Some(cookie) => {
-            let refresh_token = cookie.value();
-
-            info!("/logout -> refresh_token: {:?}", refresh_token);
-
-            match UserToken::delete(refresh_token.to_string(), &pool).await {
-                Ok(_) => {
-                    info!("/logout -> cookie_to_remove: {:?}", cookie.clone());
-                    http_response.add_removal_cookie(&cookie).unwrap();
-                    info!("/logout -> response: {:?}", http_response);
-                    Ok(http_response)
-                }
-                Err(e) => Ok(HttpResponse::InternalServerError().json(e.to_string())),
-            }
-        }
-        None => Ok(HttpResponse::Unauthorized().json("No `refresh_token` found")),
-    }
+    let cookie = request
+        .cookie("refresh_token")
+        .ok_or(MyError::Unauthenticated {
+            desc: "No `refresh_token` found".to_string(),
+        })?;
+    let refresh_token = cookie.value();
+
+    info!("/logout -> refresh_token: {:?}", refresh_token);
+
+    UserToken::delete(refresh_token.to_string(), &pool).await?;
+
+    info!("/logout -> cookie_to_remove: {:?}", cookie.clone());
+    http_response.add_removal_cookie(&cookie).unwrap();
+    info!("/logout -> response: {:?}", http_response);
+    Ok(http_response)
 }
 
 #[post("/forgot")]
 pub async fn forgot(
     data: web::Json<ForgotRequest>,
     request: HttpRequest,
+    templates: web::Data<EmailTemplates>,
     pool: web::Data<DbPool>,
-) -> Result<HttpResponse, Error> {
+) -> Result<HttpResponse, MyError> {
     let email = data.0.email;
-    let token = (Alphanumeric.sample_string(&mut rand::thread_rng(), 10)).to_lowercase();
     info!("/forgot -> email: {}", &email);
-    info!("/forgot -> rand token: {}", &token);
 
-    match Reset::insert(email.clone(), token.clone(), &pool).await {
-        Ok(_) => {
-            let frontend_url =
-                std::env::var("FRONTEND_URL").expect("Missed 'FRONTEND_URL' environment variable");
-            dbg!(&frontend_url);
+    // Same response, in the same time, whether the email is registered or not
+    let accept_language = accept_language(&request).map(str::to_string);
+    let pool = pool.get_ref().clone();
+    actix_web::rt::spawn(async move {
+        let result = send_reset_email(email, accept_language, &templates, &pool).await;
+        if let Err(e) = result {
+            info!("/forgot -> send_reset_email: {}", e);
+        }
+    });
 
-            let reset_url = format!("{frontend_url}/reset/{token}");
-            dbg!(&reset_url);
+    let response = MessageResponse {
+        message: "success".to_string(),
+    };
+    Ok(HttpResponse::Ok().json(response))
+}
 
-            let email_body = format!("Click <a href={reset_url}> here</a> to reset password");
-            let email = lettre_email::EmailBuilder::new()
-                .to("hello@example.com")
-                .from("no-reply@site.com")
-                .subject("Reset your password")
-                .html(email_body)
-                .build()
-                .unwrap();
+/// Email a reset link to the user registered with `email`, if any
+async fn send_reset_email(
+    email: String,
+    accept_language: Option<String>,
+    templates: &EmailTemplates,
+    pool: &DbPool,
+) -> Result<(), MyError> {
+    let user = match User::find_by_email(email, pool).await {
+        Ok(user) => user,
+        Err(MyError::NotFound { .. }) => return Ok(()),
+        Err(e) => return Err(e),
+    };
 
-            info!("/forgot -> email: {:?}", &email);
+    // Only the hash of the token is stored, a new request invalidates the previous links
+    let token = random_token(32);
+    let now = chrono::Utc::now().naive_utc();
+    let new_reset = Reset {
+        token_hash: hash_token(&token),
+        email: user.email.clone(),
+        created_at: now,
+        expires_at: now + Duration::minutes(30),
+        used_at: None,
+    };
 
-            let mut mailer = lettre::SmtpClient::new("localhost:1025", ClientSecurity::None)
-                .unwrap()
-                .transport();
+    let frontend_url =
+        std::env::var("FRONTEND_URL").expect("Missed 'FRONTEND_URL' environment variable");
 
-            let result = mailer.send(email.into());
-            info!("/forgot -> mailer.send: {:?}", &result);
+    let variables = serde_json::json!({
+        "first_name": user.first_name,
+        "reset_url": format!("{frontend_url}/reset/{token}"),
+    });
+    let locale = templates.locale_for(user.locale.as_deref(), accept_language.as_deref());
+    let reset_email = templates.render("reset", &locale, &user.email, variables)?;
+    Reset::insert(new_reset, reset_email, pool).await?;
 
-            let response = MessageResponse {
-                message: format!("success"),
-            };
-            Ok(HttpResponse::Ok().json(response))
-        }
-        Err(e) => Ok(HttpResponse::InternalServerError().json(e.to_string())),
-    }
+    Ok(())
 }
 
 #[post("/reset")]
 pub async fn reset(
     data: web::Json<ResetRequest>,
     request: HttpRequest,
+    hasher: web::Data<Hasher>,
     pool: web::Data<DbPool>,
-) -> Result<HttpResponse, Error> {
-    // check that password is the same with password_confirmation
-    if data.0.password != data.0.password_confirm {
-        return Ok(HttpResponse::BadRequest().json("Passwords do not match!"));
-    }
+) -> Result<HttpResponse, MyError> {
+    let incoming_password = data.0.password.clone();
 
-    info!("/reset -> incoming_data: {:?}", &data);
-    let incoming_token = data.0.token;
-    let incoming_password = data.0.password;
+    // get the unused, unexpired Reset object related to the hash of incoming token from db
+    let reset = Reset::find_valid(hash_token(&data.0.token), &pool).await?;
 
-    // get Reset object related to incoming_token from db
-    match Reset::find_by_token(incoming_token.clone(), &pool).await {
-        Ok(reset) => {
-            info!("/reset -> reset: {:?}", &reset);
+    // get User object related to email of Reset object.
+    let user = User::find_by_email(reset.email.clone(), &pool).await?;
+    info!("/reset -> user: {:?}", &user);
 
-            // if incoming_token not same with token from db, return "Invalid link!"
-            if reset.token != incoming_token {
-                return Ok(HttpResponse::BadRequest().json("Invalid link!"));
-            }
-            let email = reset.email.clone();
-
-            // get User object related to email of Reset object.
-            match User::find_by_email(email, &pool).await {
-                Ok(user) => {
-                    info!("/reset -> user: {:?}", &user);
-
-                    // update User object password
-                    // Return message:success
-                    match User::update_password(user.id, incoming_password, &pool).await {
-                        Ok(_) => {
-                            let response = MessageResponse {
-                                message: format!("success"),
-                            };
-                            Ok(HttpResponse::Ok().json(response))
-                        }
-                        Err(e) => Ok(HttpResponse::Unauthorized().json(e.to_string())),
-                    }
-                }
-                // if User object not found, return "User not found!"
-                Err(e) => Ok(HttpResponse::NotFound().json(e.to_string())),
-            }
+    let user_inputs = vec![
+        user.email.clone(),
+        user.first_name.clone(),
+        user.last_name.clone(),
+    ];
+    PasswordPolicy::from_env()
+        .validate(
+            incoming_password.clone(),
+            &data.0.password_confirm,
+            user_inputs,
+        )
+        .await?;
+
+    // update User object password, consuming the token and signing out every session
+    // Return message:success
+    User::reset_password(user.id, reset.token_hash, incoming_password, &hasher, &pool).await?;
+
+    let response = MessageResponse {
+        message: "success".to_string(),
+    };
+    Ok(HttpResponse::Ok().json(response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::DbClientConn;
+    use crate::entity::general::OutboxEmail;
+    use crate::mailer::{Email, Mailer, MemoryMailer};
+    use actix_web::{http::StatusCode, test, App};
+    use diesel::prelude::*;
+
+    /// Runs against the database of `DATABASE_URL`, the account and its emails are deleted
+    /// afterwards. Only the email queued for this account is read, others stay in the outbox.
+    #[actix_web::test]
+    #[ignore = "needs the database of DATABASE_URL"]
+    async fn register_emails_a_single_use_verification_link() {
+        let pool = DbClientConn::get_pool_connection();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(Hasher::from_env()))
+                .app_data(web::Data::new(EmailTemplates::from_env()))
+                .configure(routes_config),
+        )
+        .await;
+        let email = format!("ada.{}@example.com", random_token(12).to_lowercase());
+
+        let request = test::TestRequest::post()
+            .uri("/register")
+            .insert_header(("accept-language", "fr-CH, en;q=0.5"))
+            .set_json(serde_json::json!({
+                "first_name": "Ada",
+                "last_name": "Lovelace",
+                "email": email,
+                "password": "copper lantern harbor",
+                "password_confirm": "copper lantern harbor",
+            }))
+            .to_request();
+        let registered = test::call_service(&app, request).await.status();
+
+        let recipient = email.clone();
+        let queued: Vec<OutboxEmail> = pool
+            .run(move |connection| {
+                use crate::schema::email_outbox;
+                Ok(email_outbox::table
+                    .filter(email_outbox::recipient.eq(recipient))
+                    .load(connection)?)
+            })
+            .await
+            .unwrap();
+        let inbox = MemoryMailer::default();
+        for queued in queued {
+            let email = Email::new(
+                queued.recipient,
+                queued.subject,
+                queued.html_body,
+                queued.text_body,
+            );
+            inbox.send(&email).unwrap();
         }
-        Err(e) => Ok(HttpResponse::NotFound().json(e.to_string())),
+        let sent = inbox.sent_to(&email);
+
+        let frontend_url = std::env::var("FRONTEND_URL").unwrap();
+        let token = sent
+            .first()
+            .and_then(|sent| sent.links().into_iter().next())
+            .and_then(|link| {
+                Some(
+                    link.strip_prefix(&format!("{frontend_url}/verify/"))?
+                        .to_string(),
+                )
+            })
+            .unwrap_or_default();
+        let mut verified = vec![];
+        for _ in 0..2 {
+            let request = test::TestRequest::get()
+                .uri(&format!("/verify/{token}"))
+                .to_request();
+            verified.push(test::call_service(&app, request).await.status());
+        }
+
+        let cleanup = email.clone();
+        pool.run(move |connection| {
+            use crate::schema::{email_outbox, users};
+            diesel::delete(email_outbox::table.filter(email_outbox::recipient.eq(&cleanup)))
+                .execute(connection)?;
+            diesel::delete(users::table.filter(users::email.eq(&cleanup))).execute(connection)?;
+            Ok(())
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(registered, StatusCode::OK);
+        assert_eq!(sent.len(), 1);
+        let sent = &sent[0].email;
+        assert_eq!(sent.subject, "Vérifiez votre adresse email");
+        assert!(
+            sent.text_body.starts_with("Bonjour Ada,"),
+            "{}",
+            sent.text_body
+        );
+        assert!(
+            sent.html_body.contains("Bonjour Ada,"),
+            "{}",
+            sent.html_body
+        );
+        assert_eq!(token.len(), 32, "{}", sent.text_body);
+        assert_eq!(verified, [StatusCode::OK, StatusCode::BAD_REQUEST]);
     }
 }
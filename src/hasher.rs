@@ -0,0 +1,213 @@
+use log::info;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+
+use crate::password::PasswordScheme;
+use crate::utils::random_token;
+use crate::MyError;
+
+/// Upper bounds, in milliseconds, of the hash latency histogram buckets
+const LATENCY_BUCKETS: [u64; 7] = [50, 100, 250, 500, 1000, 2500, 5000];
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Dedicated threads hashing and verifying passwords, so they never block a request worker.
+/// Jobs wait in a bounded queue, when it is full callers get a `503` instead of piling up.
+pub struct Hasher {
+    scheme: PasswordScheme,
+    /// Hash of a random password with `scheme`, verified when there is no stored hash to check
+    dummy_hash: String,
+    sender: SyncSender<Job>,
+    queue_size: usize,
+    metrics: Arc<HashMetrics>,
+}
+
+#[derive(Default)]
+pub struct HashMetrics {
+    queue_depth: AtomicUsize,
+    completed: AtomicU64,
+    rejected: AtomicU64,
+    latency_sum_ms: AtomicU64,
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+}
+
+impl Hasher {
+    /// Start the pool.
+    /// `HASH_WORKERS` (default one per CPU) and `HASH_QUEUE_SIZE` (default 16) are optional.
+    pub fn from_env() -> Self {
+        let workers = std::env::var("HASH_WORKERS")
+            .ok()
+            .and_then(|workers| workers.parse().ok())
+            .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()));
+        let queue_size = std::env::var("HASH_QUEUE_SIZE")
+            .ok()
+            .and_then(|size| size.parse().ok())
+            .unwrap_or(16);
+        info!("HASH_WORKERS: {workers}, HASH_QUEUE_SIZE: {queue_size}");
+
+        Hasher::new(PasswordScheme::from_env(), workers, queue_size)
+    }
+
+    pub fn new(scheme: PasswordScheme, workers: usize, queue_size: usize) -> Self {
+        let (sender, receiver) = sync_channel::<Job>(queue_size);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let metrics = Arc::new(HashMetrics::default());
+
+        for i in 0..workers {
+            let receiver = receiver.clone();
+            let metrics = metrics.clone();
+            thread::Builder::new()
+                .name(format!("hasher-{i}"))
+                .spawn(move || work(receiver, metrics))
+                .expect("could not start hasher thread");
+        }
+
+        let dummy_hash = scheme
+            .hash(&random_token(32))
+            .expect("could not hash the dummy password");
+
+        Hasher {
+            scheme,
+            dummy_hash,
+            sender,
+            queue_size,
+            metrics,
+        }
+    }
+
+    pub async fn hash(&self, password: String) -> Result<String, MyError> {
+        let scheme = self.scheme;
+        self.run(move || scheme.hash(&password)).await
+    }
+
+    pub async fn verify(&self, password: String, hashed: String) -> Result<bool, MyError> {
+        self.run(move || PasswordScheme::verify(&password, &hashed))
+            .await
+    }
+
+    /// Verify `password` against a throwaway hash made with the current scheme.
+    /// Called for unknown users, so that they take as long to reject as a wrong password.
+    pub async fn verify_dummy(&self, password: String) -> Result<(), MyError> {
+        self.verify(password, self.dummy_hash.clone())
+            .await
+            .map(|_| ())
+    }
+
+    /// Whether `hashed` should be replaced by a hash made with the current scheme
+    pub fn needs_rehash(&self, hashed: &str) -> bool {
+        self.scheme.needs_rehash(hashed)
+    }
+
+    /// Queue `job` and wait for its result, failing right away if the queue is full
+    async fn run<F, T>(&self, job: F) -> Result<T, MyError>
+    where
+        F: FnOnce() -> Result<T, MyError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let (result_sender, result_receiver) = oneshot::channel();
+        let metrics = self.metrics.clone();
+        let job: Job = Box::new(move || {
+            let start = Instant::now();
+            let result = job();
+            metrics.observe(start.elapsed());
+            let _ = result_sender.send(result);
+        });
+
+        self.metrics.queue_depth.fetch_add(1, Ordering::Relaxed);
+        match self.sender.try_send(job) {
+            Ok(()) => {}
+            Err(e) => {
+                self.metrics.queue_depth.fetch_sub(1, Ordering::Relaxed);
+                return Err(match e {
+                    TrySendError::Full(_) => {
+                        self.metrics.rejected.fetch_add(1, Ordering::Relaxed);
+                        MyError::ServiceUnavailable {
+                            desc: "Password hashing queue is full".to_string(),
+                        }
+                    }
+                    TrySendError::Disconnected(_) => MyError::Internal {
+                        desc: "Password hashing pool stopped".to_string(),
+                    },
+                });
+            }
+        }
+
+        result_receiver.await.map_err(|e| MyError::Internal {
+            desc: format!("{}", e),
+        })?
+    }
+
+    /// Metrics in the Prometheus text format
+    pub fn render_metrics(&self) -> String {
+        let metrics = &self.metrics;
+        let mut text = String::new();
+
+        text += "# HELP hash_queue_depth Password hashing jobs waiting for a worker.\n";
+        text += "# TYPE hash_queue_depth gauge\n";
+        text += &format!(
+            "hash_queue_depth {}\n",
+            metrics.queue_depth.load(Ordering::Relaxed)
+        );
+        text += "# HELP hash_queue_size Capacity of the password hashing queue.\n";
+        text += "# TYPE hash_queue_size gauge\n";
+        text += &format!("hash_queue_size {}\n", self.queue_size);
+        text += "# HELP hash_rejected_total Password hashing jobs refused because the queue was full.\n";
+        text += "# TYPE hash_rejected_total counter\n";
+        text += &format!(
+            "hash_rejected_total {}\n",
+            metrics.rejected.load(Ordering::Relaxed)
+        );
+
+        text += "# HELP hash_duration_seconds Time spent hashing or verifying a password.\n";
+        text += "# TYPE hash_duration_seconds histogram\n";
+        let mut cumulative = 0;
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&metrics.latency_buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            text += &format!(
+                "hash_duration_seconds_bucket{{le=\"{}\"}} {cumulative}\n",
+                *bound as f64 / 1000.0
+            );
+        }
+        let completed = metrics.completed.load(Ordering::Relaxed);
+        text += &format!("hash_duration_seconds_bucket{{le=\"+Inf\"}} {completed}\n");
+        text += &format!(
+            "hash_duration_seconds_sum {}\n",
+            metrics.latency_sum_ms.load(Ordering::Relaxed) as f64 / 1000.0
+        );
+        text += &format!("hash_duration_seconds_count {completed}\n");
+
+        text
+    }
+}
+
+impl HashMetrics {
+    fn observe(&self, elapsed: Duration) {
+        let ms = elapsed.as_millis() as u64;
+        self.completed.fetch_add(1, Ordering::Relaxed);
+        self.latency_sum_ms.fetch_add(ms, Ordering::Relaxed);
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|bound| ms <= *bound) {
+            self.latency_buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Take jobs off the queue until the pool is dropped
+fn work(receiver: Arc<Mutex<Receiver<Job>>>, metrics: Arc<HashMetrics>) {
+    loop {
+        let job = match receiver.lock() {
+            Ok(receiver) => receiver.recv(),
+            Err(_) => return,
+        };
+        match job {
+            Ok(job) => {
+                metrics.queue_depth.fetch_sub(1, Ordering::Relaxed);
+                job();
+            }
+            Err(_) => return,
+        }
+    }
+}
@@ -0,0 +1,112 @@
+use crate::{
+    db::DbPool,
+    entity::user::{User, UserImportError, UserImportReport, UserImportRow},
+    MyError,
+};
+
+/// Encodings accepted by a bulk user import
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportFormat {
+    /// Comma-separated values with a `first_name,last_name,email,password_hash[,email_verified]` header
+    Csv,
+    /// One JSON object per line, with the same fields
+    JsonLines,
+}
+
+impl ImportFormat {
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        match content_type.split(';').next().unwrap_or_default().trim() {
+            "text/csv" => Some(ImportFormat::Csv),
+            "application/x-ndjson" | "application/jsonl" | "application/jsonlines" => {
+                Some(ImportFormat::JsonLines)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn from_path(path: &str) -> Option<Self> {
+        match path.rsplit('.').next()? {
+            "csv" => Some(ImportFormat::Csv),
+            "jsonl" | "ndjson" => Some(ImportFormat::JsonLines),
+            _ => None,
+        }
+    }
+}
+
+/// Insert every valid row of `data`.
+/// A failing row is reported with its line and does not stop the others.
+pub async fn import_users(format: ImportFormat, data: &[u8], pool: &DbPool) -> UserImportReport {
+    let mut report = UserImportReport {
+        imported: 0,
+        failed: 0,
+        errors: vec![],
+    };
+
+    for (line, row) in parse_rows(format, data) {
+        let email = row.as_ref().ok().map(|row| row.email.clone());
+        let result = match row {
+            Ok(row) => User::import(row, pool).await.map(|_| ()),
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Ok(()) => report.imported += 1,
+            Err(e) => {
+                report.failed += 1;
+                report.errors.push(UserImportError {
+                    line,
+                    email,
+                    code: e.code().to_string(),
+                    detail: e.detail(),
+                });
+            }
+        }
+    }
+
+    report
+}
+
+fn parse_rows(format: ImportFormat, data: &[u8]) -> Vec<(u64, Result<UserImportRow, MyError>)> {
+    match format {
+        ImportFormat::Csv => {
+            let mut reader = csv::ReaderBuilder::new()
+                .trim(csv::Trim::All)
+                .from_reader(data);
+            let headers = match reader.headers() {
+                Ok(headers) => headers.clone(),
+                Err(e) => return vec![(1, Err(invalid_row(e)))],
+            };
+
+            reader
+                .records()
+                .map(|record| match record {
+                    Ok(record) => (
+                        record.position().map_or(0, |position| position.line()),
+                        record.deserialize(Some(&headers)).map_err(invalid_row),
+                    ),
+                    Err(e) => (
+                        e.position().map_or(0, |position| position.line()),
+                        Err(invalid_row(e)),
+                    ),
+                })
+                .collect()
+        }
+        ImportFormat::JsonLines => String::from_utf8_lossy(data)
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(i, line)| {
+                (
+                    i as u64 + 1,
+                    serde_json::from_str(line).map_err(invalid_row),
+                )
+            })
+            .collect(),
+    }
+}
+
+fn invalid_row(e: impl std::fmt::Display) -> MyError {
+    MyError::Validation {
+        desc: e.to_string(),
+    }
+}
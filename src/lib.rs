@@ -1,24 +1,214 @@
 #![allow(dead_code)]
 #![allow(unused_variables)]
 #![allow(unused_imports)]
+#![allow(non_local_definitions)]
 
 #[macro_use]
 extern crate diesel;
 extern crate diesel_migrations;
 
 pub mod auth;
+pub mod challenge;
 pub mod db;
+pub mod dev_inbox;
+pub mod dkim;
+pub mod email_templates;
 pub mod engine;
 pub mod entity;
 pub mod handler;
+pub mod hasher;
+pub mod import;
+pub mod lockout;
+pub mod mailer;
+pub mod outbox;
+pub mod password;
+pub mod password_policy;
+pub mod rate_limit;
 pub mod schema;
 pub mod utils;
 
 // type MyError = Box<dyn std::error::Error + Send + Sync>;
 
 extern crate custom_error;
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
 use custom_error::custom_error;
+use entity::general::{FieldError, ProblemDetails};
 
 custom_error! { pub MyError
-    General{desc:String}  = "Error: {desc}",
+    NotFound{desc:String} = "{desc}",
+    Conflict{desc:String} = "{desc}",
+    Unauthenticated{desc:String} = "{desc}",
+    Forbidden{desc:String} = "{desc}",
+    Validation{desc:String} = "{desc}",
+    InvalidFields{errors:Vec<FieldError>} = @{ invalid_fields_message(errors) },
+    Gone{desc:String} = "{desc}",
+    RateLimited{retry_after:i64} = "Too many requests, retry after {retry_after} seconds",
+    StepUpRequired{max_age:i64, acr_values:String} = "Step-up authentication required",
+    ChallengeRequired{desc:String} = "{desc}",
+    EmailNotVerified = "Email not verified",
+    ServiceUnavailable{desc:String} = "Service unavailable: {desc}",
+    Internal{desc:String} = "Internal error: {desc}",
+}
+
+fn invalid_fields_message(errors: &[FieldError]) -> String {
+    let mut fields: Vec<&str> = errors.iter().map(|e| e.field.as_str()).collect();
+    fields.dedup();
+    format!("Invalid fields: {}", fields.join(", "))
+}
+
+impl From<diesel::result::Error> for MyError {
+    fn from(e: diesel::result::Error) -> Self {
+        match e {
+            diesel::result::Error::NotFound => MyError::NotFound {
+                desc: "Not found".to_string(),
+            },
+            diesel::result::Error::DatabaseError(
+                diesel::result::DatabaseErrorKind::UniqueViolation,
+                _,
+            ) => MyError::Conflict {
+                desc: "Already exists".to_string(),
+            },
+            e => MyError::Internal {
+                desc: format!("{}", e),
+            },
+        }
+    }
+}
+
+/// The pool only fails when no connection could be obtained in time
+impl From<r2d2::Error> for MyError {
+    fn from(e: r2d2::Error) -> Self {
+        MyError::ServiceUnavailable {
+            desc: format!("{}", e),
+        }
+    }
+}
+
+impl From<bcrypt::BcryptError> for MyError {
+    fn from(e: bcrypt::BcryptError) -> Self {
+        MyError::Internal {
+            desc: format!("{}", e),
+        }
+    }
+}
+
+impl From<argon2::Error> for MyError {
+    fn from(e: argon2::Error) -> Self {
+        MyError::Internal {
+            desc: format!("{}", e),
+        }
+    }
+}
+
+impl From<argon2::password_hash::Error> for MyError {
+    fn from(e: argon2::password_hash::Error) -> Self {
+        MyError::Internal {
+            desc: format!("{}", e),
+        }
+    }
+}
+
+impl MyError {
+    /// Stable machine-readable code of the error, the frontend can switch on it
+    pub fn code(&self) -> &'static str {
+        match self {
+            MyError::NotFound { .. } => "not_found",
+            MyError::Conflict { .. } => "conflict",
+            MyError::Unauthenticated { .. } => "unauthenticated",
+            MyError::Forbidden { .. } => "forbidden",
+            MyError::Validation { .. } => "validation_failed",
+            MyError::InvalidFields { .. } => "validation_failed",
+            MyError::Gone { .. } => "gone",
+            MyError::RateLimited { .. } => "rate_limited",
+            MyError::StepUpRequired { .. } => "step_up_required",
+            MyError::ChallengeRequired { .. } => "challenge_required",
+            MyError::EmailNotVerified => "email_not_verified",
+            MyError::ServiceUnavailable { .. } => "service_unavailable",
+            MyError::Internal { .. } => "internal_error",
+        }
+    }
+
+    /// Human-readable detail which is safe to show to clients
+    pub fn detail(&self) -> String {
+        // internal details are logged, never sent to clients
+        match self {
+            MyError::Internal { desc } => {
+                log::error!("{}", desc);
+                "An unexpected error occurred.".to_string()
+            }
+            MyError::ServiceUnavailable { desc } => {
+                log::error!("{}", desc);
+                "The service is temporarily unavailable.".to_string()
+            }
+            e => e.to_string(),
+        }
+    }
+}
+
+/// Errors are answered as RFC 7807 `application/problem+json`
+impl ResponseError for MyError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            MyError::NotFound { .. } => StatusCode::NOT_FOUND,
+            MyError::Conflict { .. } => StatusCode::CONFLICT,
+            MyError::Unauthenticated { .. } => StatusCode::UNAUTHORIZED,
+            MyError::Forbidden { .. } => StatusCode::FORBIDDEN,
+            MyError::Validation { .. } => StatusCode::BAD_REQUEST,
+            MyError::InvalidFields { .. } => StatusCode::BAD_REQUEST,
+            MyError::Gone { .. } => StatusCode::GONE,
+            MyError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            MyError::StepUpRequired { .. } => StatusCode::UNAUTHORIZED,
+            MyError::ChallengeRequired { .. } => StatusCode::PRECONDITION_REQUIRED,
+            MyError::EmailNotVerified => StatusCode::FORBIDDEN,
+            MyError::ServiceUnavailable { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            MyError::Internal { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let status = self.status_code();
+        let detail = self.detail();
+
+        let mut problem = ProblemDetails {
+            problem_type: "about:blank".to_string(),
+            title: status.canonical_reason().unwrap_or_default().to_string(),
+            status: status.as_u16(),
+            detail,
+            code: self.code().to_string(),
+            max_age: None,
+            acr_values: None,
+            retry_after: None,
+            errors: None,
+        };
+
+        let mut response = HttpResponse::build(status);
+        response.content_type("application/problem+json");
+
+        match self {
+            MyError::StepUpRequired {
+                max_age,
+                acr_values,
+            } => {
+                // RFC 9470 challenge
+                response.insert_header((
+                    actix_web::http::header::WWW_AUTHENTICATE,
+                    format!(
+                        "Bearer error=\"insufficient_user_authentication\", max_age=\"{max_age}\", acr_values=\"{acr_values}\""
+                    ),
+                ));
+                problem.max_age = Some(*max_age);
+                problem.acr_values = Some(acr_values.clone());
+            }
+            MyError::InvalidFields { errors } => {
+                problem.errors = Some(errors.clone());
+            }
+            MyError::RateLimited { retry_after } => {
+                response.insert_header((actix_web::http::header::RETRY_AFTER, *retry_after));
+                problem.retry_after = Some(*retry_after);
+            }
+            _ => {}
+        }
+
+        response.json(problem)
+    }
 }
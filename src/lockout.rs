@@ -0,0 +1,167 @@
+use actix_web::HttpRequest;
+use chrono::{Duration, NaiveDateTime};
+
+use crate::utils::{client_ip, normalize_email};
+
+/// Failures after which logins are first delayed, then refused for a while
+#[derive(Debug, Clone, Copy)]
+pub struct Thresholds {
+    pub delay_after: i32,
+    pub lockout_after: i32,
+}
+
+/// Limits on failed logins, counted per account and per client IP.
+/// Read from `LOGIN_DELAY_AFTER` (default 3), `LOGIN_LOCKOUT_AFTER` (default 10),
+/// `LOGIN_IP_DELAY_AFTER` (default 20), `LOGIN_IP_LOCKOUT_AFTER` (default 100),
+/// `LOGIN_LOCKOUT_MINUTES` (default 15) and `LOGIN_FAILURE_WINDOW_MINUTES` (default 60).
+#[derive(Debug, Clone)]
+pub struct LockoutPolicy {
+    pub account: Thresholds,
+    pub ip: Thresholds,
+    pub lockout: Duration,
+    /// Failures older than this are forgotten
+    pub window: Duration,
+}
+
+/// How long a login is refused after a failure
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Block {
+    None,
+    Delay(Duration),
+    Lockout(Duration),
+}
+
+impl LockoutPolicy {
+    pub fn from_env() -> Self {
+        let number = |name: &str, default: i64| {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+                .max(1)
+        };
+
+        LockoutPolicy {
+            account: Thresholds {
+                delay_after: number("LOGIN_DELAY_AFTER", 3) as i32,
+                lockout_after: number("LOGIN_LOCKOUT_AFTER", 10) as i32,
+            },
+            ip: Thresholds {
+                delay_after: number("LOGIN_IP_DELAY_AFTER", 20) as i32,
+                lockout_after: number("LOGIN_IP_LOCKOUT_AFTER", 100) as i32,
+            },
+            lockout: Duration::minutes(number("LOGIN_LOCKOUT_MINUTES", 15)),
+            window: Duration::minutes(number("LOGIN_FAILURE_WINDOW_MINUTES", 60)),
+        }
+    }
+
+    /// Block following `failures` consecutive failures.
+    /// Delays double with each failure past `delay_after` and never exceed the lockout.
+    pub fn block(&self, thresholds: Thresholds, failures: i32) -> Block {
+        if failures >= thresholds.lockout_after {
+            Block::Lockout(self.lockout)
+        } else if failures >= thresholds.delay_after {
+            let exponent = (failures - thresholds.delay_after).min(30) as u32;
+            let delay = Duration::seconds(2i64.pow(exponent));
+            Block::Delay(delay.min(self.lockout))
+        } else {
+            Block::None
+        }
+    }
+}
+
+impl Block {
+    /// Until when logins are refused, for a failure at `now`
+    pub fn until(&self, now: NaiveDateTime) -> Option<NaiveDateTime> {
+        match *self {
+            Block::None => None,
+            Block::Delay(duration) | Block::Lockout(duration) => Some(now + duration),
+        }
+    }
+}
+
+/// Counter key of the account of `email`, whether it is registered or not
+pub fn account_key(email: &str) -> String {
+    format!("email:{}", normalize_email(email))
+}
+
+/// Counter key of the client, see `client_ip`
+pub fn ip_key(request: &HttpRequest) -> String {
+    match client_ip(request) {
+        Some(address) => format!("ip:{address}"),
+        None => "ip:unknown".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> LockoutPolicy {
+        LockoutPolicy {
+            account: Thresholds {
+                delay_after: 3,
+                lockout_after: 10,
+            },
+            ip: Thresholds {
+                delay_after: 20,
+                lockout_after: 100,
+            },
+            lockout: Duration::minutes(15),
+            window: Duration::minutes(60),
+        }
+    }
+
+    #[test]
+    fn delays_double_past_the_threshold() {
+        let policy = policy();
+
+        assert_eq!(policy.block(policy.account, 0), Block::None);
+        assert_eq!(policy.block(policy.account, 2), Block::None);
+        assert_eq!(
+            policy.block(policy.account, 3),
+            Block::Delay(Duration::seconds(1))
+        );
+        assert_eq!(
+            policy.block(policy.account, 4),
+            Block::Delay(Duration::seconds(2))
+        );
+        assert_eq!(
+            policy.block(policy.account, 9),
+            Block::Delay(Duration::seconds(64))
+        );
+        assert_eq!(
+            policy.block(policy.account, 10),
+            Block::Lockout(Duration::minutes(15))
+        );
+    }
+
+    #[test]
+    fn delays_never_exceed_the_lockout() {
+        let policy = policy();
+
+        assert_eq!(
+            policy.block(policy.ip, 29),
+            Block::Delay(Duration::seconds(512))
+        );
+        assert_eq!(
+            policy.block(policy.ip, 99),
+            Block::Delay(Duration::minutes(15))
+        );
+        assert_eq!(
+            policy.block(policy.ip, 100),
+            Block::Lockout(Duration::minutes(15))
+        );
+    }
+
+    #[test]
+    fn blocks_start_at_the_failure() {
+        let now = chrono::NaiveDate::from_ymd(2022, 4, 24).and_hms(9, 0, 0);
+
+        assert_eq!(Block::None.until(now), None);
+        assert_eq!(
+            Block::Delay(Duration::seconds(4)).until(now),
+            Some(now + Duration::seconds(4))
+        );
+    }
+}
@@ -0,0 +1,424 @@
+use chrono::NaiveDateTime;
+use lettre::smtp::authentication::Credentials;
+use lettre::smtp::ConnectionReuseParameters;
+use lettre::{
+    ClientSecurity, ClientTlsParameters, SendableEmail, SmtpClient, SmtpConnectionManager,
+    Transport,
+};
+use log::{info, warn};
+use native_tls::TlsConnector;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::dkim::DkimSigner;
+use crate::utils::normalize_email;
+use crate::utils::random_token;
+use crate::MyError;
+
+/// Emails kept by the `MemoryMailer`, the oldest are dropped past this
+const MEMORY_MAILER_CAPACITY: usize = 1000;
+
+/// An outgoing email, with HTML and plain-text versions of the body
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub html_body: String,
+    pub text_body: String,
+}
+
+impl Email {
+    pub fn new(
+        to: impl Into<String>,
+        subject: impl Into<String>,
+        html_body: String,
+        text_body: String,
+    ) -> Self {
+        Email {
+            to: to.into(),
+            subject: subject.into(),
+            html_body,
+            text_body,
+        }
+    }
+
+    /// The email as a `multipart/alternative` RFC 5322 message from `from`, signed by `dkim`
+    fn to_sendable(&self, from: &str, dkim: Option<&DkimSigner>) -> Result<SendableEmail, MyError> {
+        let sendable: SendableEmail = lettre_email::EmailBuilder::new()
+            .to(self.to.as_str())
+            .from(from)
+            .subject(encode_header(&self.subject))
+            .alternative(self.html_body.as_str(), self.text_body.as_str())
+            .build()
+            .map_err(|e| MyError::Internal {
+                desc: format!("{}", e),
+            })?
+            .into();
+
+        let dkim = match dkim {
+            Some(dkim) => dkim,
+            None => return Ok(sendable),
+        };
+        let envelope = sendable.envelope().clone();
+        let message_id = sendable.message_id().to_string();
+        let message = sendable
+            .message_to_string()
+            .map_err(|e| MyError::Internal {
+                desc: format!("{}", e),
+            })?;
+        let signed = dkim.sign(&message)?;
+        Ok(SendableEmail::new(
+            envelope,
+            message_id,
+            signed.into_bytes(),
+        ))
+    }
+}
+
+/// RFC 2047 encoded words of a non-ASCII header value, headers must be ASCII
+fn encode_header(value: &str) -> String {
+    if value.is_ascii() {
+        return value.to_string();
+    }
+
+    // encoded words are at most 75 characters, so at most 45 bytes each, never splitting a character
+    let mut words = Vec::new();
+    let mut chunk = String::new();
+    for character in value.chars() {
+        if chunk.len() + character.len_utf8() > 45 {
+            words.push(format!("=?utf-8?b?{}?=", base64::encode(&chunk)));
+            chunk.clear();
+        }
+        chunk.push(character);
+    }
+    words.push(format!("=?utf-8?b?{}?=", base64::encode(&chunk)));
+    words.join(" ")
+}
+
+/// Where outgoing emails go, used by the outbox `Dispatcher`.
+/// `send` blocks until the email is accepted, handlers queue emails in the outbox instead.
+pub trait Mailer: Send + Sync {
+    fn send(&self, email: &Email) -> Result<(), MyError>;
+
+    /// The backend as a `MemoryMailer`, whose emails the dev inbox shows
+    fn as_memory(&self) -> Option<&MemoryMailer> {
+        None
+    }
+}
+
+/// Choose the backend from `MAILER`: `smtp` (default), `file` or `memory`.
+/// Messages are DKIM signed when `DKIM_PRIVATE_KEY_FILE` is set, see `DkimSigner`.
+pub fn from_env() -> Arc<dyn Mailer> {
+    let from = std::env::var("MAIL_FROM").unwrap_or_else(|_| "no-reply@site.com".to_string());
+    let backend = std::env::var("MAILER").unwrap_or_default();
+    info!("MAILER: {backend}, MAIL_FROM: {from}");
+    let dkim = DkimSigner::from_env(&from);
+
+    match backend.trim() {
+        "file" => Arc::new(FileMailer::from_env(from, dkim)),
+        "memory" => Arc::new(MemoryMailer::default()),
+        _ => Arc::new(SmtpMailer::from_env(from, dkim)),
+    }
+}
+
+/// Sends through an SMTP server over a pool of reused connections.
+/// Read from `SMTP_HOST` (default `localhost`), `SMTP_PORT` (default `1025`),
+/// `SMTP_TLS` (`none`, the default, `starttls` or `tls`), `SMTP_USERNAME`, `SMTP_PASSWORD`
+/// and `SMTP_POOL_SIZE` (default 4).
+pub struct SmtpMailer {
+    pool: r2d2::Pool<SmtpConnectionManager>,
+    from: String,
+    dkim: Option<DkimSigner>,
+}
+
+impl SmtpMailer {
+    pub fn from_env(from: String, dkim: Option<DkimSigner>) -> Self {
+        let host = std::env::var("SMTP_HOST").unwrap_or_else(|_| "localhost".to_string());
+        let port: u16 = std::env::var("SMTP_PORT")
+            .ok()
+            .and_then(|port| port.parse().ok())
+            .unwrap_or(1025);
+        let pool_size: u32 = std::env::var("SMTP_POOL_SIZE")
+            .ok()
+            .and_then(|size| size.parse().ok())
+            .unwrap_or(4);
+        let tls = std::env::var("SMTP_TLS").unwrap_or_default();
+        info!("SMTP_HOST: {host}, SMTP_PORT: {port}, SMTP_TLS: {tls}, SMTP_POOL_SIZE: {pool_size}");
+
+        let tls_parameters = || {
+            let connector = TlsConnector::new().expect("could not build the TLS connector");
+            ClientTlsParameters::new(host.clone(), connector)
+        };
+        let security = match tls.trim() {
+            "starttls" => ClientSecurity::Required(tls_parameters()),
+            "tls" => ClientSecurity::Wrapper(tls_parameters()),
+            _ => ClientSecurity::None,
+        };
+
+        let mut client = SmtpClient::new((host.as_str(), port), security)
+            .expect("could not resolve SMTP_HOST")
+            .connection_reuse(ConnectionReuseParameters::ReuseUnlimited)
+            .timeout(Some(Duration::from_secs(10)));
+        if let Ok(username) = std::env::var("SMTP_USERNAME") {
+            let password = std::env::var("SMTP_PASSWORD").unwrap_or_default();
+            client = client.credentials(Credentials::new(username, password));
+        }
+
+        // Connections are opened on first use, the server may start after the application
+        let pool = r2d2::Pool::builder()
+            .max_size(pool_size)
+            .min_idle(Some(0))
+            .connection_timeout(Duration::from_secs(10))
+            .build_unchecked(
+                SmtpConnectionManager::new(client).expect("could not build the SMTP client"),
+            );
+
+        SmtpMailer { pool, from, dkim }
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, email: &Email) -> Result<(), MyError> {
+        let mut transport = self.pool.get().map_err(|e| MyError::Internal {
+            desc: format!("{}", e),
+        })?;
+        transport
+            .send(email.to_sendable(&self.from, self.dkim.as_ref())?)
+            .map(|_| ())
+            .map_err(|e| MyError::Internal {
+                desc: format!("{}", e),
+            })
+    }
+}
+
+/// Writes every email as an `.eml` file into the `new` folder of the `MAIL_DIR` maildir
+/// (default `mail`), to read them during development without an SMTP server
+pub struct FileMailer {
+    dir: PathBuf,
+    from: String,
+    dkim: Option<DkimSigner>,
+}
+
+impl FileMailer {
+    pub fn from_env(from: String, dkim: Option<DkimSigner>) -> Self {
+        let dir = PathBuf::from(std::env::var("MAIL_DIR").unwrap_or_else(|_| "mail".to_string()));
+        info!("MAIL_DIR: {}", dir.display());
+
+        for folder in ["tmp", "new", "cur"] {
+            if let Err(e) = std::fs::create_dir_all(dir.join(folder)) {
+                warn!("MAIL_DIR {}: {}", dir.display(), e);
+            }
+        }
+
+        FileMailer { dir, from, dkim }
+    }
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, email: &Email) -> Result<(), MyError> {
+        let message = email
+            .to_sendable(&self.from, self.dkim.as_ref())?
+            .message_to_string()
+            .map_err(|e| MyError::Internal {
+                desc: format!("{}", e),
+            })?;
+
+        // Written to `tmp` first, so readers of `new` never see a partial message
+        let name = format!(
+            "{}.{}.eml",
+            chrono::Utc::now().timestamp_millis(),
+            random_token(8)
+        );
+        let tmp = self.dir.join("tmp").join(&name);
+        std::fs::write(&tmp, message)
+            .and_then(|_| std::fs::rename(&tmp, self.dir.join("new").join(&name)))
+            .map_err(|e| MyError::Internal {
+                desc: format!("{}", e),
+            })
+    }
+}
+
+/// An email kept by the `MemoryMailer`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SentEmail {
+    /// Increasing, never reused even after `clear`
+    pub id: u64,
+    pub sent_at: NaiveDateTime,
+    pub email: Email,
+}
+
+impl SentEmail {
+    /// Every distinct `http` and `https` link of the bodies, text body first
+    pub fn links(&self) -> Vec<String> {
+        // HTML bodies escape their variables, links included
+        let html = ["&#x2F;", "&#x27;", "&quot;", "&lt;", "&gt;", "&amp;"]
+            .iter()
+            .zip(["/", "'", "\"", "<", ">", "&"])
+            .fold(self.email.html_body.clone(), |html, (entity, character)| {
+                html.replace(entity, character)
+            });
+        let mut links: Vec<String> = Vec::new();
+        for body in [self.email.text_body.as_str(), html.as_str()] {
+            for (start, _) in body.match_indices("http") {
+                let link: String = body[start..]
+                    .chars()
+                    .take_while(|c| !c.is_whitespace() && !"\"'<>()".contains(*c))
+                    .collect();
+                let is_link = link.starts_with("http://") || link.starts_with("https://");
+                if is_link && !links.contains(&link) {
+                    links.push(link);
+                }
+            }
+        }
+        links
+    }
+}
+
+#[derive(Default)]
+struct Inbox {
+    next_id: u64,
+    emails: VecDeque<SentEmail>,
+}
+
+/// Keeps the latest emails in memory instead of sending them, for development and tests,
+/// where they are browsable at `/dev/mail`.
+/// Clones share the same inbox, keep one to inspect what the application sent.
+#[derive(Clone, Default)]
+pub struct MemoryMailer {
+    inbox: Arc<Mutex<Inbox>>,
+}
+
+impl MemoryMailer {
+    /// Emails sent so far, oldest first
+    pub fn sent(&self) -> Vec<SentEmail> {
+        self.inbox
+            .lock()
+            .map(|inbox| inbox.emails.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Emails sent to `to`, whatever its case, newest first
+    pub fn sent_to(&self, to: &str) -> Vec<SentEmail> {
+        let to = normalize_email(to);
+        let mut sent: Vec<SentEmail> = self
+            .sent()
+            .into_iter()
+            .filter(|sent| normalize_email(&sent.email.to) == to)
+            .collect();
+        sent.reverse();
+        sent
+    }
+
+    pub fn find(&self, id: u64) -> Option<SentEmail> {
+        self.sent().into_iter().find(|sent| sent.id == id)
+    }
+
+    /// Forget every email, and return how many there were
+    pub fn clear(&self) -> usize {
+        self.inbox
+            .lock()
+            .map(|mut inbox| inbox.emails.drain(..).count())
+            .unwrap_or_default()
+    }
+}
+
+impl Mailer for MemoryMailer {
+    fn send(&self, email: &Email) -> Result<(), MyError> {
+        let mut inbox = self.inbox.lock().map_err(|e| MyError::Internal {
+            desc: format!("{}", e),
+        })?;
+
+        inbox.next_id += 1;
+        let sent = SentEmail {
+            id: inbox.next_id,
+            sent_at: chrono::Utc::now().naive_utc(),
+            email: email.clone(),
+        };
+        inbox.emails.push_back(sent);
+        if inbox.emails.len() > MEMORY_MAILER_CAPACITY {
+            inbox.emails.pop_front();
+        }
+
+        Ok(())
+    }
+
+    fn as_memory(&self) -> Option<&MemoryMailer> {
+        Some(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sent(text_body: &str, html_body: &str) -> SentEmail {
+        SentEmail {
+            id: 1,
+            sent_at: chrono::Utc::now().naive_utc(),
+            email: Email::new(
+                "jane@example.com",
+                "Subject",
+                html_body.to_string(),
+                text_body.to_string(),
+            ),
+        }
+    }
+
+    #[test]
+    fn finds_links_of_both_bodies() {
+        let sent = sent(
+            "Verify: https://example.com/verify/abc\nHelp (http://example.com/help).",
+            "<a href=\"https:&#x2F;&#x2F;example.com&#x2F;verify&#x2F;abc\">verify</a> \
+             <a href='https://example.com/reset?token=1&amp;next=%2F'>reset</a>",
+        );
+
+        assert_eq!(
+            sent.links(),
+            [
+                "https://example.com/verify/abc",
+                "http://example.com/help",
+                "https://example.com/reset?token=1&next=%2F",
+            ]
+        );
+    }
+
+    #[test]
+    fn ignores_words_starting_like_links() {
+        let sent = sent("See httpbin or http:/broken and https//broken", "");
+
+        assert!(sent.links().is_empty());
+    }
+
+    #[test]
+    fn memory_mailer_keeps_emails_by_recipient() {
+        let mailer = MemoryMailer::default();
+        let inbox = mailer.clone();
+        for (to, subject) in [
+            ("jane@example.com", "1"),
+            ("john@example.com", "2"),
+            ("Jane@Example.com", "3"),
+        ] {
+            mailer
+                .send(&Email::new(
+                    to,
+                    subject,
+                    "<p>html</p>".to_string(),
+                    "text".to_string(),
+                ))
+                .unwrap();
+        }
+
+        let subjects: Vec<String> = inbox
+            .sent_to("JANE@example.com")
+            .into_iter()
+            .map(|sent| sent.email.subject)
+            .collect();
+        assert_eq!(subjects, ["3", "1"]);
+        assert_eq!(inbox.find(2).unwrap().email.to, "john@example.com");
+        assert_eq!(inbox.clear(), 3);
+        assert!(inbox.sent().is_empty());
+    }
+}
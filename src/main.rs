@@ -1,3 +1,4 @@
+#[allow(unused_imports)]
 use actix_cors::Cors;
 use actix_identity::{CookieIdentityPolicy, IdentityService};
 use actix_session::{storage::CookieSessionStore, SessionMiddleware};
@@ -7,7 +8,20 @@ use actix_web::{
     App, HttpServer,
 };
 use log::info;
-use rust_training::{db::DbClientConn, handler, utils};
+use rust_training::{
+    auth,
+    challenge::{Challenges, ProofOfWork},
+    db::DbClientConn,
+    dev_inbox,
+    email_templates::EmailTemplates,
+    handler,
+    hasher::Hasher,
+    mailer,
+    outbox::Dispatcher,
+    rate_limit::{RateLimit, RateLimiter},
+    utils,
+};
+use std::sync::Arc;
 
 #[actix_web::main] // or #[tokio::main]
 async fn main() -> std::io::Result<()> {
@@ -18,15 +32,49 @@ async fn main() -> std::io::Result<()> {
     // let server = Server::http(address.as_str()).expect("Failed to start server.");
     // info!("Tiny server started");
 
+    // Checked now rather than on the first sign-in link or remembered device
+    auth::secret("LOGIN_LINK_SECRET");
+    auth::secret("DEVICE_SECRET");
+
     let pool = DbClientConn::get_pool_connection();
+    let rate_limiter = Arc::new(RateLimiter::from_env(pool.clone()));
+    let challenges = Data::new(Challenges::from_env(pool.clone()));
+    let mailer = mailer::from_env();
+    // Only with `MAILER=memory`, never in production
+    let inbox = mailer.as_memory().cloned().map(Data::new);
+    actix_web::rt::spawn(Dispatcher::new(mailer, pool.clone()).run());
     let data = Data::new(pool);
+    let hasher = Data::new(Hasher::from_env());
+    let templates = Data::new(EmailTemplates::from_env());
 
     let address = std::env::var("ADDRESS").expect("Missed 'ADDRESS' environment variable");
+    // Not under `/api`: keep it on an internal interface, reachable by the scraper only
+    let metrics_address = std::env::var("METRICS_ADDRESS")
+        .ok()
+        .filter(|address| !address.trim().is_empty());
 
     let session_key = cookie::Key::generate();
 
+    let metrics_server = match metrics_address {
+        Some(metrics_address) => {
+            info!("Metrics address: {metrics_address}");
+            let hasher = hasher.clone();
+            Some(
+                HttpServer::new(move || {
+                    App::new()
+                        .app_data(hasher.clone())
+                        .service(handler::metrics)
+                })
+                .workers(1)
+                .bind(metrics_address)?
+                .run(),
+            )
+        }
+        None => None,
+    };
+
     info!("Server address: {address}");
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         let identity_policy = CookieIdentityPolicy::new(&[0; 32])
             .name("auth-cookie")
             .secure(false);
@@ -39,9 +87,27 @@ async fn main() -> std::io::Result<()> {
             ))
             .wrap(IdentityService::new(identity_policy))
             .app_data(data.clone())
-            .service(web::scope("/api").configure(handler::routes_config))
+            .app_data(hasher.clone())
+            .app_data(templates.clone())
+            .app_data(challenges.clone())
+            .service(
+                web::scope("/api")
+                    .wrap(ProofOfWork::new(challenges.clone()))
+                    .wrap(RateLimit::new(rate_limiter.clone()))
+                    .configure(handler::routes_config),
+            )
+            .configure(|config| {
+                if let Some(inbox) = &inbox {
+                    config.app_data(inbox.clone());
+                    dev_inbox::routes_config(config);
+                }
+            })
     })
     .bind(address)?
-    .run()
-    .await
+    .run();
+
+    match metrics_server {
+        Some(metrics_server) => futures::try_join!(server, metrics_server).map(|_| ()),
+        None => server.await,
+    }
 }
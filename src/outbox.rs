@@ -0,0 +1,224 @@
+use actix_web::web;
+use chrono::{Duration, NaiveDateTime};
+use log::{info, warn};
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::db::DbPool;
+use crate::entity::general::OutboxEmail;
+use crate::mailer::{Email, Mailer};
+use crate::MyError;
+
+/// Claimed emails are hidden from other dispatchers this long, more than a delivery can take
+const CLAIM_LEASE_SECONDS: i64 = 300;
+
+/// Delivered and dead emails are pruned this often
+const PRUNE_EVERY_SECONDS: u64 = 3600;
+
+/// How the outbox is delivered.
+/// Read from `OUTBOX_POLL_SECONDS` (default 2), `OUTBOX_BATCH_SIZE` (default 20),
+/// `OUTBOX_MAX_ATTEMPTS` (default 8), `OUTBOX_RETRY_SECONDS` (default 30),
+/// `OUTBOX_MAX_RETRY_SECONDS` (default 3600), `OUTBOX_RECIPIENT_LIMIT` (default 10),
+/// `OUTBOX_RECIPIENT_WINDOW_MINUTES` (default 60) and `OUTBOX_RETENTION_DAYS` (default 7).
+#[derive(Debug, Clone)]
+pub struct OutboxPolicy {
+    pub poll: std::time::Duration,
+    pub batch_size: i64,
+    /// Failed deliveries after which an email is dead
+    pub max_attempts: i32,
+    /// Wait after the first failure, doubled after each following one
+    pub retry: Duration,
+    pub max_retry: Duration,
+    /// Emails delivered to one recipient per `recipient_window`, further ones wait
+    pub recipient_limit: usize,
+    pub recipient_window: Duration,
+    /// Delivered and dead emails are kept this long
+    pub retention: Duration,
+}
+
+impl OutboxPolicy {
+    pub fn from_env() -> Self {
+        let number = |name: &str, default: i64| {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+                .max(1)
+        };
+
+        OutboxPolicy {
+            poll: std::time::Duration::from_secs(number("OUTBOX_POLL_SECONDS", 2) as u64),
+            batch_size: number("OUTBOX_BATCH_SIZE", 20),
+            max_attempts: number("OUTBOX_MAX_ATTEMPTS", 8) as i32,
+            retry: Duration::seconds(number("OUTBOX_RETRY_SECONDS", 30)),
+            max_retry: Duration::seconds(number("OUTBOX_MAX_RETRY_SECONDS", 3600)),
+            recipient_limit: number("OUTBOX_RECIPIENT_LIMIT", 10) as usize,
+            recipient_window: Duration::minutes(number("OUTBOX_RECIPIENT_WINDOW_MINUTES", 60)),
+            retention: Duration::days(number("OUTBOX_RETENTION_DAYS", 7)),
+        }
+    }
+
+    /// When to retry after `attempts` failed deliveries, `None` once the email is dead
+    pub fn retry_at(&self, attempts: i32, now: NaiveDateTime) -> Option<NaiveDateTime> {
+        if attempts >= self.max_attempts {
+            return None;
+        }
+
+        let exponent = (attempts - 1).clamp(0, 30) as u32;
+        let delay = self
+            .retry
+            .num_seconds()
+            .saturating_mul(2i64.pow(exponent))
+            .min(self.max_retry.num_seconds());
+        Some(now + Duration::seconds(delay))
+    }
+}
+
+/// Delivers the emails of the outbox through a `Mailer`.
+/// Every instance may run one, a claimed email is only delivered by the dispatcher which claimed it.
+pub struct Dispatcher {
+    policy: OutboxPolicy,
+    mailer: Arc<dyn Mailer>,
+    pool: DbPool,
+}
+
+impl Dispatcher {
+    pub fn new(mailer: Arc<dyn Mailer>, pool: DbPool) -> Self {
+        let policy = OutboxPolicy::from_env();
+        info!("outbox policy: {:?}", policy);
+
+        Dispatcher {
+            policy,
+            mailer,
+            pool,
+        }
+    }
+
+    /// Deliver due emails until the application stops, spawned at startup
+    pub async fn run(self) {
+        let mut last_prune: Option<Instant> = None;
+        loop {
+            if last_prune.is_none_or(|last| last.elapsed().as_secs() >= PRUNE_EVERY_SECONDS) {
+                let before = chrono::Utc::now().naive_utc() - self.policy.retention;
+                match OutboxEmail::prune(before, &self.pool).await {
+                    Ok(pruned) => info!("outbox -> {} delivered or dead emails pruned", pruned),
+                    Err(e) => warn!("outbox -> prune: {}", e),
+                }
+                last_prune = Some(Instant::now());
+            }
+
+            match self.dispatch().await {
+                // a full batch means more emails are probably due
+                Ok(claimed) if claimed as i64 == self.policy.batch_size => continue,
+                Ok(_) => {}
+                Err(e) => warn!("outbox -> dispatch: {}", e),
+            }
+            tokio::time::sleep(self.policy.poll).await;
+        }
+    }
+
+    /// Deliver one batch of due emails and return how many were claimed
+    pub async fn dispatch(&self) -> Result<usize, MyError> {
+        let lease = Duration::seconds(CLAIM_LEASE_SECONDS);
+        let emails = OutboxEmail::claim(self.policy.batch_size, lease, &self.pool).await?;
+
+        let claimed = emails.len();
+        for email in emails {
+            let id = email.id;
+            if let Err(e) = self.deliver(email).await {
+                warn!("outbox {} -> deliver: {}", id, e);
+            }
+        }
+
+        Ok(claimed)
+    }
+
+    async fn deliver(&self, outbox_email: OutboxEmail) -> Result<(), MyError> {
+        let now = chrono::Utc::now().naive_utc();
+
+        // Past the limit, wait until the oldest delivery in the window leaves it
+        let since = now - self.policy.recipient_window;
+        let sent = OutboxEmail::sent_to(outbox_email.recipient.clone(), since, &self.pool).await?;
+        if sent.len() >= self.policy.recipient_limit {
+            let until =
+                sent[sent.len() - self.policy.recipient_limit] + self.policy.recipient_window;
+            return OutboxEmail::postpone(outbox_email.id, until, &self.pool).await;
+        }
+
+        let email = Email::new(
+            outbox_email.recipient,
+            outbox_email.subject,
+            outbox_email.html_body,
+            outbox_email.text_body,
+        );
+        let mailer = self.mailer.clone();
+        let result = match web::block(move || mailer.send(&email)).await {
+            Ok(result) => result,
+            Err(e) => Err(MyError::Internal {
+                desc: format!("{}", e),
+            }),
+        };
+
+        match result {
+            Ok(()) => OutboxEmail::mark_sent(outbox_email.id, &self.pool).await,
+            Err(e) => {
+                let retry_at = self.policy.retry_at(outbox_email.attempts + 1, now);
+                if retry_at.is_none() {
+                    warn!("outbox {} -> dead after {}", outbox_email.id, e);
+                }
+                OutboxEmail::mark_failed(outbox_email.id, e.to_string(), retry_at, &self.pool).await
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> OutboxPolicy {
+        OutboxPolicy {
+            poll: std::time::Duration::from_secs(2),
+            batch_size: 20,
+            max_attempts: 8,
+            retry: Duration::seconds(30),
+            max_retry: Duration::seconds(3600),
+            recipient_limit: 10,
+            recipient_window: Duration::minutes(60),
+            retention: Duration::days(7),
+        }
+    }
+
+    #[test]
+    fn retries_back_off_exponentially() {
+        let policy = policy();
+        let now = chrono::NaiveDate::from_ymd(2022, 4, 24).and_hms(9, 0, 0);
+        let retry_in = |attempts| {
+            policy
+                .retry_at(attempts, now)
+                .map(|at| (at - now).num_seconds())
+        };
+
+        assert_eq!(retry_in(1), Some(30));
+        assert_eq!(retry_in(2), Some(60));
+        assert_eq!(retry_in(4), Some(240));
+        assert_eq!(retry_in(7), Some(1920));
+    }
+
+    #[test]
+    fn retries_are_capped_then_stop() {
+        let policy = OutboxPolicy {
+            max_attempts: 40,
+            ..policy()
+        };
+        let now = chrono::NaiveDate::from_ymd(2022, 4, 24).and_hms(9, 0, 0);
+
+        assert_eq!(policy.retry_at(8, now), Some(now + Duration::seconds(3600)));
+        assert_eq!(
+            policy.retry_at(39, now),
+            Some(now + Duration::seconds(3600))
+        );
+        assert_eq!(policy.retry_at(40, now), None);
+        assert_eq!(self::policy().retry_at(8, now), None);
+    }
+}
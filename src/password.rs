@@ -0,0 +1,422 @@
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+use bcrypt::HashParts;
+use hmac::Hmac;
+use log::info;
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+use std::time::{Duration, Instant};
+use subtle::ConstantTimeEq;
+
+use crate::MyError;
+
+/// bcrypt ignores the bytes of a password after the 72nd
+pub const BCRYPT_MAX_PASSWORD_BYTES: usize = 72;
+
+/// Algorithm and parameters used to hash new passwords.
+/// Stored hashes are self-describing (`$2b$<cost>$...` or `$argon2id$v=19$m=..,t=..,p=..$...`),
+/// so any scheme can verify them and tell whether they are outdated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PasswordScheme {
+    Bcrypt {
+        cost: u32,
+    },
+    Argon2id {
+        memory_kib: u32,
+        iterations: u32,
+        parallelism: u32,
+    },
+}
+
+impl PasswordScheme {
+    /// Read the scheme from `PASSWORD_HASH` (`argon2id`, the default, or `bcrypt`) and its costs from
+    /// `ARGON2_MEMORY_KIB` (default 19456), `ARGON2_ITERATIONS` (default 2), `ARGON2_PARALLELISM`
+    /// (default 1) or `BCRYPT_COST` (default 12).
+    /// With `PASSWORD_HASH_TARGET_MS`, the iterations or the cost are then raised at startup until
+    /// one hash takes that long, the configured ones staying the floor.
+    pub fn from_env() -> Self {
+        let number = |name: &str, default: u32| {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        };
+
+        let scheme = if Self::bcrypt_from_env() {
+            PasswordScheme::Bcrypt {
+                cost: number("BCRYPT_COST", bcrypt::DEFAULT_COST),
+            }
+        } else {
+            PasswordScheme::Argon2id {
+                memory_kib: number("ARGON2_MEMORY_KIB", 19456),
+                iterations: number("ARGON2_ITERATIONS", 2),
+                parallelism: number("ARGON2_PARALLELISM", 1),
+            }
+        };
+
+        let scheme = match number("PASSWORD_HASH_TARGET_MS", 0) {
+            0 => scheme,
+            target => scheme.calibrate(Duration::from_millis(target.into())),
+        };
+
+        info!("PASSWORD_HASH: {scheme:?}");
+
+        scheme
+    }
+
+    /// Whether `PASSWORD_HASH` selects bcrypt, without reading nor calibrating the costs
+    pub fn bcrypt_from_env() -> bool {
+        std::env::var("PASSWORD_HASH")
+            .unwrap_or_default()
+            .trim()
+            .eq_ignore_ascii_case("bcrypt")
+    }
+
+    /// Raise the iterations (Argon2id) or the cost (bcrypt) until hashing takes at least `target`,
+    /// never going below those of `self`
+    pub fn calibrate(self, target: Duration) -> Self {
+        let mut scheme = self;
+
+        loop {
+            let start = Instant::now();
+            if scheme.hash("calibration").is_err() || start.elapsed() >= target {
+                return scheme;
+            }
+            scheme = match scheme {
+                PasswordScheme::Bcrypt { cost } if cost < 31 => {
+                    PasswordScheme::Bcrypt { cost: cost + 1 }
+                }
+                PasswordScheme::Argon2id {
+                    memory_kib,
+                    iterations,
+                    parallelism,
+                } if iterations < 100 => PasswordScheme::Argon2id {
+                    memory_kib,
+                    iterations: iterations + 1,
+                    parallelism,
+                },
+                _ => return scheme,
+            };
+        }
+    }
+
+    /// Hash `password` with this scheme and a random salt
+    pub fn hash(&self, password: &str) -> Result<String, MyError> {
+        match *self {
+            PasswordScheme::Bcrypt { cost } => Ok(bcrypt::hash(password, cost)?),
+            PasswordScheme::Argon2id {
+                memory_kib,
+                iterations,
+                parallelism,
+            } => {
+                let params = Params::new(memory_kib, iterations, parallelism, None)?;
+                let salt = SaltString::generate(&mut rand::rngs::OsRng);
+                Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+                    .hash_password(password.as_bytes(), &salt)?
+                    .to_string())
+            }
+        }
+    }
+
+    /// Check `password` against a stored hash of any supported format
+    pub fn verify(password: &str, stored: &str) -> Result<bool, MyError> {
+        if stored.starts_with("$argon2") {
+            let parsed = PasswordHash::new(stored)?;
+            match Argon2::default().verify_password(password.as_bytes(), &parsed) {
+                Ok(()) => Ok(true),
+                Err(argon2::password_hash::Error::Password) => Ok(false),
+                Err(e) => Err(e.into()),
+            }
+        } else if stored.starts_with("$2") {
+            Ok(bcrypt::verify(password, stored)?)
+        } else {
+            match LegacyHash::parse(stored) {
+                Some(legacy) => legacy.verify(password),
+                None => Err(MyError::Internal {
+                    desc: "Unsupported password hash format".to_string(),
+                }),
+            }
+        }
+    }
+
+    /// Whether `stored` is a hash which `verify` understands
+    pub fn is_supported(stored: &str) -> bool {
+        if stored.starts_with("$argon2") {
+            PasswordHash::new(stored).is_ok()
+        } else if stored.starts_with("$2") {
+            stored.parse::<HashParts>().is_ok()
+        } else {
+            LegacyHash::parse(stored).is_some()
+        }
+    }
+
+    /// Whether `stored` was made with another algorithm or costs below those of this scheme.
+    /// Costlier hashes are kept, lowering the costs does not rehash every password.
+    pub fn needs_rehash(&self, stored: &str) -> bool {
+        match *self {
+            PasswordScheme::Bcrypt { cost } => stored
+                .parse::<HashParts>()
+                .map_or(true, |parts| parts.get_cost() < cost),
+            PasswordScheme::Argon2id {
+                memory_kib,
+                iterations,
+                ..
+            } => match PasswordHash::new(stored) {
+                Ok(parsed) if parsed.algorithm == Algorithm::Argon2id.ident() => {
+                    Params::try_from(&parsed).map_or(true, |params| {
+                        params.m_cost() < memory_kib || params.t_cost() < iterations
+                    })
+                }
+                _ => true,
+            },
+        }
+    }
+}
+
+/// Formats of hashes imported from other applications.
+/// They are only verified, the next successful login replaces them with the current scheme.
+enum LegacyHash<'a> {
+    /// Django `pbkdf2_sha256$<iterations>$<salt>$<base64 hash>`
+    Pbkdf2Sha256 {
+        iterations: u32,
+        salt: &'a str,
+        hash: Vec<u8>,
+    },
+    /// Django `scrypt$<n>$<salt>$<r>$<p>$<base64 hash>`
+    Scrypt {
+        salt: &'a str,
+        params: scrypt::Params,
+        hash: Vec<u8>,
+    },
+    /// Django `bcrypt_sha256$<bcrypt hash>`, bcrypt of the hex SHA-256 of the password
+    BcryptSha256 { hash: &'a str },
+    /// `sha1$<salt>$<hex hash>`, SHA-1 of the salt followed by the password
+    Sha1 { salt: &'a str, hash: Vec<u8> },
+    /// `sha256$<salt>$<hex hash>`, SHA-256 of the salt followed by the password
+    Sha256 { salt: &'a str, hash: Vec<u8> },
+    /// `sha512$<salt>$<hex hash>`, SHA-512 of the salt followed by the password
+    Sha512 { salt: &'a str, hash: Vec<u8> },
+}
+
+impl<'a> LegacyHash<'a> {
+    fn parse(stored: &'a str) -> Option<Self> {
+        let (algorithm, rest) = stored.split_once('$')?;
+        let parts: Vec<&str> = rest.split('$').collect();
+
+        let legacy = match (algorithm, parts.as_slice()) {
+            ("pbkdf2_sha256", [iterations, salt, hash]) => LegacyHash::Pbkdf2Sha256 {
+                iterations: iterations.parse().ok().filter(|i| *i > 0)?,
+                salt,
+                hash: base64::decode(hash).ok()?,
+            },
+            ("scrypt", [n, salt, r, p, hash]) => {
+                let n: u64 = n.parse().ok().filter(|n: &u64| n.is_power_of_two())?;
+                LegacyHash::Scrypt {
+                    salt,
+                    params: scrypt::Params::new(
+                        n.trailing_zeros() as u8,
+                        r.parse().ok()?,
+                        p.parse().ok()?,
+                    )
+                    .ok()?,
+                    hash: base64::decode(hash).ok()?,
+                }
+            }
+            ("bcrypt_sha256", _) => {
+                rest.parse::<HashParts>().ok()?;
+                LegacyHash::BcryptSha256 { hash: rest }
+            }
+            ("sha1", [salt, hash]) => LegacyHash::Sha1 {
+                salt,
+                hash: hex::decode(hash).ok()?,
+            },
+            ("sha256", [salt, hash]) => LegacyHash::Sha256 {
+                salt,
+                hash: hex::decode(hash).ok()?,
+            },
+            ("sha512", [salt, hash]) => LegacyHash::Sha512 {
+                salt,
+                hash: hex::decode(hash).ok()?,
+            },
+            _ => return None,
+        };
+
+        match &legacy {
+            LegacyHash::Pbkdf2Sha256 { hash, .. } | LegacyHash::Scrypt { hash, .. }
+                if hash.is_empty() =>
+            {
+                None
+            }
+            _ => Some(legacy),
+        }
+    }
+
+    fn verify(&self, password: &str) -> Result<bool, MyError> {
+        let (computed, expected) = match self {
+            LegacyHash::Pbkdf2Sha256 {
+                iterations,
+                salt,
+                hash,
+            } => {
+                let mut computed = vec![0; hash.len()];
+                pbkdf2::pbkdf2::<Hmac<Sha256>>(
+                    password.as_bytes(),
+                    salt.as_bytes(),
+                    *iterations,
+                    &mut computed,
+                );
+                (computed, hash)
+            }
+            LegacyHash::Scrypt { salt, params, hash } => {
+                let mut computed = vec![0; hash.len()];
+                scrypt::scrypt(password.as_bytes(), salt.as_bytes(), params, &mut computed)
+                    .map_err(|e| MyError::Internal {
+                        desc: format!("{}", e),
+                    })?;
+                (computed, hash)
+            }
+            LegacyHash::BcryptSha256 { hash } => {
+                let digest = hex::encode(Sha256::digest(password.as_bytes()));
+                return Ok(bcrypt::verify(digest, hash)?);
+            }
+            LegacyHash::Sha1 { salt, hash } => (salted_digest::<Sha1>(salt, password), hash),
+            LegacyHash::Sha256 { salt, hash } => (salted_digest::<Sha256>(salt, password), hash),
+            LegacyHash::Sha512 { salt, hash } => (salted_digest::<Sha512>(salt, password), hash),
+        };
+
+        Ok(computed.ct_eq(expected).into())
+    }
+}
+
+fn salted_digest<D: Digest>(salt: &str, password: &str) -> Vec<u8> {
+    let mut hasher = D::new();
+    hasher.update(salt.as_bytes());
+    hasher.update(password.as_bytes());
+    hasher.finalize().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `make_password("lètmein", "seasalt", "scrypt")` of the Django test suite
+    const DJANGO_SCRYPT: &str = "scrypt$16384$seasalt$8$1$Qj3+9PPyRjSJIebHnG81TMjsqtaIGxNQG/aEB/NYafTJ7tibgfYz71m0ldQESkXFRkdVCBhhY8mx7rQwite/Pw==";
+
+    #[test]
+    fn verifies_django_scrypt_hashes() {
+        assert!(PasswordScheme::is_supported(DJANGO_SCRYPT));
+        assert!(PasswordScheme::verify("lètmein", DJANGO_SCRYPT).unwrap());
+        assert!(!PasswordScheme::verify("letmein", DJANGO_SCRYPT).unwrap());
+    }
+
+    #[test]
+    fn parses_legacy_hashes() {
+        match LegacyHash::parse(DJANGO_SCRYPT) {
+            Some(LegacyHash::Scrypt { salt, params, hash }) => {
+                assert_eq!(salt, "seasalt");
+                assert_eq!((params.log_n(), params.r(), params.p()), (14, 8, 1));
+                assert_eq!(hash.len(), 64);
+            }
+            _ => panic!("not parsed as scrypt"),
+        }
+
+        match LegacyHash::parse(
+            "pbkdf2_sha256$1000$seasalt$JgZryXe2Ga8ysg6XbzkLpTdyPQrHqsinbL9BnnhgX4A=",
+        ) {
+            Some(LegacyHash::Pbkdf2Sha256 {
+                iterations, salt, ..
+            }) => assert_eq!((iterations, salt), (1000, "seasalt")),
+            _ => panic!("not parsed as pbkdf2_sha256"),
+        }
+
+        assert!(matches!(
+            LegacyHash::parse("sha1$seasalt$cff36ea83f5706ce9aa7454e63e431fc726b2dc8"),
+            Some(LegacyHash::Sha1 {
+                salt: "seasalt",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn rejects_malformed_legacy_hashes() {
+        for stored in [
+            // salt and n swapped, the order this parser used to expect
+            "scrypt$seasalt$16384$8$1$Qj3+9PPyRjSJIebHnG81TMjsqtaIGxNQG/aEB/NYafTJ7tibgfYz71m0ldQESkXFRkdVCBhhY8mx7rQwite/Pw==",
+            "scrypt$1000$seasalt$8$1$Qj3+",
+            "pbkdf2_sha256$0$seasalt$JgZryXe2Ga8ysg6XbzkLpTdyPQrHqsinbL9BnnhgX4A=",
+            "pbkdf2_sha256$1000$seasalt$",
+            "pbkdf2_sha256$1000$seasalt",
+            "sha1$seasalt$not-hex",
+            "bcrypt_sha256$not-bcrypt",
+            "md5$seasalt$0123456789abcdef",
+            "plaintext",
+        ] {
+            assert!(LegacyHash::parse(stored).is_none(), "{stored}");
+            assert!(!PasswordScheme::is_supported(stored), "{stored}");
+        }
+    }
+
+    #[test]
+    fn verifies_legacy_hashes() {
+        let digest = hex::encode(Sha256::digest("lètmein".as_bytes()));
+        let bcrypt_sha256 = format!("bcrypt_sha256${}", bcrypt::hash(digest, 4).unwrap());
+
+        for stored in [
+            "pbkdf2_sha256$1000$seasalt$JgZryXe2Ga8ysg6XbzkLpTdyPQrHqsinbL9BnnhgX4A=",
+            "sha1$seasalt$cff36ea83f5706ce9aa7454e63e431fc726b2dc8",
+            "sha256$seasalt$e0327e0c88846ec7f85601380e86c72a5242e3455a1ae0f736f349858f126eb9",
+            bcrypt_sha256.as_str(),
+        ] {
+            assert!(
+                PasswordScheme::verify("lètmein", stored).unwrap(),
+                "{stored}"
+            );
+            assert!(
+                !PasswordScheme::verify("letmein", stored).unwrap(),
+                "{stored}"
+            );
+        }
+    }
+
+    #[test]
+    fn rehashes_only_below_the_configured_costs() {
+        let scheme = PasswordScheme::Argon2id {
+            memory_kib: 1024,
+            iterations: 2,
+            parallelism: 1,
+        };
+        let hash = |memory_kib, iterations| {
+            PasswordScheme::Argon2id {
+                memory_kib,
+                iterations,
+                parallelism: 1,
+            }
+            .hash("lètmein")
+            .unwrap()
+        };
+
+        assert!(!scheme.needs_rehash(&hash(1024, 2)));
+        assert!(!scheme.needs_rehash(&hash(2048, 3)));
+        assert!(scheme.needs_rehash(&hash(512, 2)));
+        assert!(scheme.needs_rehash(&hash(1024, 1)));
+        assert!(scheme.needs_rehash(&bcrypt::hash("lètmein", 4).unwrap()));
+        assert!(scheme.needs_rehash("sha1$seasalt$cff36ea83f5706ce9aa7454e63e431fc726b2dc8"));
+    }
+
+    #[test]
+    fn calibrates_from_the_configured_costs() {
+        let floor = PasswordScheme::Argon2id {
+            memory_kib: 1024,
+            iterations: 3,
+            parallelism: 1,
+        };
+        assert_eq!(floor.calibrate(Duration::ZERO), floor);
+
+        let calibrated = PasswordScheme::Bcrypt { cost: 4 }.calibrate(Duration::from_millis(50));
+        let start = Instant::now();
+        calibrated.hash("lètmein").unwrap();
+        assert!(matches!(calibrated, PasswordScheme::Bcrypt { cost } if cost > 4));
+        assert!(start.elapsed() >= Duration::from_millis(25));
+    }
+}
@@ -0,0 +1,596 @@
+use actix_web::web;
+use log::warn;
+use sha1::{Digest, Sha1};
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+
+use crate::entity::general::FieldError;
+use crate::entity::user::{PasswordFeedback, PasswordStrengthResponse};
+use crate::password::{PasswordScheme, BCRYPT_MAX_PASSWORD_BYTES};
+use crate::MyError;
+
+/// Most common passwords and words found in them, most common first
+#[rustfmt::skip]
+const COMMON_PASSWORDS: &[&str] = &[
+    "123456", "password", "12345678", "qwerty", "123456789", "12345", "1234", "111111", "1234567",
+    "dragon", "123123", "baseball", "abc123", "football", "monkey", "letmein", "696969", "shadow",
+    "master", "666666", "qwertyuiop", "123321", "mustang", "1234567890", "michael", "654321",
+    "superman", "1qaz2wsx", "7777777", "121212", "000000", "qazwsx", "123qwe", "killer", "trustno1",
+    "jordan", "jennifer", "zxcvbnm", "asdfgh", "hunter", "buster", "soccer", "harley", "batman",
+    "andrew", "tigger", "sunshine", "iloveyou", "2000", "charlie", "robert", "thomas", "hockey",
+    "ranger", "daniel", "starwars", "klaster", "112233", "george", "computer", "michelle",
+    "jessica", "pepper", "1111", "zxcvbn", "555555", "11111111", "131313", "freedom", "777777",
+    "pass", "maggie", "159753", "aaaaaa", "ginger", "princess", "joshua", "cheese", "amanda",
+    "summer", "love", "ashley", "nicole", "chelsea", "biteme", "matthew", "access", "yankees",
+    "987654321", "dallas", "austin", "thunder", "taylor", "matrix", "admin", "welcome", "login",
+    "passw0rd", "hello", "secret", "solo", "flower", "whatever", "qwerty123", "football1",
+    "password1", "changeme", "default", "guest", "root", "test", "user", "monkey1", "dragon1",
+    "angel", "baby", "family", "friend", "money", "orange", "purple", "silver", "winter", "spring",
+    "autumn", "house", "happy", "lucky", "forever", "october", "november", "december", "january",
+];
+
+/// Keyboard rows, straight runs of four keys or more along them are easy to guess
+const KEYBOARD_ROWS: &[&str] = &["qwertyuiop", "asdfghjkl", "zxcvbnm", "1234567890"];
+
+/// Longest substring matched against the patterns, longer runs are covered by several matches
+const MAX_MATCH_LENGTH: usize = 32;
+
+/// Rules new passwords must follow.
+/// Read from `PASSWORD_MIN_LENGTH` (default 8 characters), `PASSWORD_MAX_LENGTH` (default 128
+/// bytes of UTF-8, at most 72 with bcrypt which ignores the following ones),
+/// `PASSWORD_MIN_CLASSES` (lowercase, uppercase, digits and symbols, default 0),
+/// `PASSWORD_MIN_SCORE` (0 to 4, default 2), `PASSWORD_HISTORY` (default 5) and
+/// `BREACHED_PASSWORDS_DIR`.
+#[derive(Debug, Clone)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    /// In bytes, as hashed
+    pub max_length: usize,
+    pub min_classes: usize,
+    pub min_score: u8,
+    /// Number of previous passwords a user may not reuse, besides the current one
+    pub history_size: usize,
+    /// Directory of SHA-1 ranges: one file per 5-character hex prefix, named like `21BD1` or
+    /// `21BD1.txt`, with `SUFFIX:COUNT` lines (the layout of the Pwned Passwords range API)
+    pub breached_passwords_dir: Option<PathBuf>,
+}
+
+impl PasswordPolicy {
+    pub fn from_env() -> Self {
+        let number = |name: &str, default: usize| {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        };
+
+        let mut max_length = number("PASSWORD_MAX_LENGTH", 128);
+        if PasswordScheme::bcrypt_from_env() {
+            max_length = max_length.min(BCRYPT_MAX_PASSWORD_BYTES);
+        }
+
+        PasswordPolicy {
+            min_length: number("PASSWORD_MIN_LENGTH", 8).max(1),
+            max_length,
+            min_classes: number("PASSWORD_MIN_CLASSES", 0).min(4),
+            min_score: number("PASSWORD_MIN_SCORE", 2).min(4) as u8,
+            history_size: number("PASSWORD_HISTORY", 5),
+            breached_passwords_dir: std::env::var("BREACHED_PASSWORDS_DIR")
+                .ok()
+                .filter(|dir| !dir.trim().is_empty())
+                .map(PathBuf::from),
+        }
+    }
+
+    /// Check `password` off the async executor.
+    /// `user_inputs` are the email and names of the user, which the password must not contain.
+    pub async fn evaluate(
+        &self,
+        password: String,
+        user_inputs: Vec<String>,
+    ) -> Result<PasswordStrengthResponse, MyError> {
+        let policy = self.clone();
+        web::block(move || policy.check(&password, &user_inputs))
+            .await
+            .map_err(|e| MyError::Internal {
+                desc: format!("{}", e),
+            })
+    }
+
+    /// Like `evaluate`, but fail with every violation, including a `password_confirm` which differs
+    pub async fn validate(
+        &self,
+        password: String,
+        password_confirm: &str,
+        user_inputs: Vec<String>,
+    ) -> Result<(), MyError> {
+        let mismatch = password != password_confirm;
+        let mut errors = self.evaluate(password, user_inputs).await?.errors;
+        if mismatch {
+            errors.push(FieldError {
+                field: "password_confirm".to_string(),
+                code: "mismatch".to_string(),
+                message: "Kindly confirm the same password.".to_string(),
+            });
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(MyError::InvalidFields { errors })
+        }
+    }
+
+    pub fn check(&self, password: &str, user_inputs: &[String]) -> PasswordStrengthResponse {
+        let mut errors = vec![];
+        let error = |code: &str, message: String| FieldError {
+            field: "password".to_string(),
+            code: code.to_string(),
+            message,
+        };
+
+        // Refused before anything else, the estimate grows with the length
+        if password.len() > self.max_length {
+            return PasswordStrengthResponse {
+                score: 0,
+                guesses_log10: 0.0,
+                breached: false,
+                valid: false,
+                feedback: PasswordFeedback {
+                    warning: None,
+                    suggestions: vec![],
+                },
+                errors: vec![error(
+                    "too_long",
+                    format!("Use at most {} bytes", self.max_length),
+                )],
+            };
+        }
+        if password.chars().count() < self.min_length {
+            errors.push(error(
+                "too_short",
+                format!("Use at least {} characters", self.min_length),
+            ));
+        }
+
+        let classes = character_classes(password);
+        if classes < self.min_classes {
+            errors.push(error(
+                "missing_character_classes",
+                format!(
+                    "Mix at least {} of lowercase letters, uppercase letters, digits and symbols",
+                    self.min_classes
+                ),
+            ));
+        }
+
+        let tokens = personal_tokens(user_inputs);
+        let lower = password.to_lowercase();
+        if tokens.iter().any(|token| lower.contains(token.as_str())) {
+            errors.push(error(
+                "contains_personal_info",
+                "Do not use your email or name".to_string(),
+            ));
+        }
+
+        let estimate = estimate(password, &tokens);
+        if estimate.score < self.min_score {
+            errors.push(error(
+                "too_weak",
+                "This password is too easy to guess".to_string(),
+            ));
+        }
+
+        let breached_count = self.breached_count(password);
+        if breached_count.is_some() {
+            errors.push(error(
+                "breached",
+                "This password appeared in a data breach, choose another one".to_string(),
+            ));
+        }
+
+        PasswordStrengthResponse {
+            score: estimate.score,
+            guesses_log10: (estimate.guesses_log10 * 100.0).round() / 100.0,
+            breached: breached_count.is_some(),
+            valid: errors.is_empty(),
+            feedback: PasswordFeedback {
+                warning: estimate.warning.map(str::to_string),
+                suggestions: estimate.suggestions.iter().map(|s| s.to_string()).collect(),
+            },
+            errors,
+        }
+    }
+
+    /// How many times `password` appears in the breached passwords corpus, if it does.
+    /// Only the range file of the first 5 characters of its SHA-1 is read.
+    fn breached_count(&self, password: &str) -> Option<u64> {
+        let dir = self.breached_passwords_dir.as_ref()?;
+        let hash = hex::encode_upper(Sha1::digest(password.as_bytes()));
+        let (prefix, suffix) = hash.split_at(5);
+
+        let file = [dir.join(prefix), dir.join(format!("{prefix}.txt"))]
+            .iter()
+            .find_map(|path| std::fs::File::open(path).ok());
+        let file = match file {
+            Some(file) => file,
+            None => {
+                if !dir.is_dir() {
+                    warn!(
+                        "BREACHED_PASSWORDS_DIR {} is not a directory",
+                        dir.display()
+                    );
+                }
+                return None;
+            }
+        };
+
+        BufReader::new(file)
+            .lines()
+            .map_while(Result::ok)
+            .find_map(|line| {
+                let (line_suffix, count) = line.trim().split_once(':')?;
+                line_suffix
+                    .eq_ignore_ascii_case(suffix)
+                    .then(|| count.trim().parse().unwrap_or(1))
+            })
+    }
+}
+
+/// Number of classes among lowercase letters, uppercase letters, digits and symbols
+fn character_classes(password: &str) -> usize {
+    [
+        password.chars().any(|c| c.is_lowercase()),
+        password.chars().any(|c| c.is_uppercase()),
+        password.chars().any(|c| c.is_numeric()),
+        password.chars().any(|c| !c.is_alphanumeric()),
+    ]
+    .iter()
+    .filter(|present| **present)
+    .count()
+}
+
+/// Lowercase words of at least 3 characters from the email and names of the user
+fn personal_tokens(user_inputs: &[String]) -> Vec<String> {
+    let mut tokens = vec![];
+    for input in user_inputs {
+        let input = input.trim().to_lowercase();
+        let input = input.split('@').next().unwrap_or_default();
+        for token in input
+            .split(|c: char| !c.is_alphanumeric())
+            .chain(std::iter::once(input))
+        {
+            if token.chars().count() >= 3 && !tokens.iter().any(|t| t == token) {
+                tokens.push(token.to_string());
+            }
+        }
+    }
+    tokens
+}
+
+struct Estimate {
+    guesses_log10: f64,
+    score: u8,
+    warning: Option<&'static str>,
+    suggestions: Vec<&'static str>,
+}
+
+/// Kinds of guessable patterns, in the order their warnings are preferred
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Pattern {
+    Common,
+    Personal,
+    Keyboard,
+    Sequence,
+    Repeat,
+    Year,
+}
+
+/// A guessable substring `start..end`, with the number of guesses needed to find it
+struct Match {
+    start: usize,
+    end: usize,
+    guesses: f64,
+    pattern: Pattern,
+}
+
+/// Estimate how many guesses an attacker needs, in the spirit of zxcvbn:
+/// the password is split into the cheapest sequence of known patterns and brute-forced characters.
+fn estimate(password: &str, personal: &[String]) -> Estimate {
+    let chars: Vec<char> = password.chars().collect();
+    let lower: Vec<char> = password.to_lowercase().chars().collect();
+    let unleet: Vec<char> = lower.iter().map(|c| unleet(*c)).collect();
+    let n = chars.len().min(lower.len());
+
+    let mut matches = vec![];
+    for start in 0..n {
+        for end in start + 3..=n.min(start + MAX_MATCH_LENGTH) {
+            let exact: String = lower[start..end].iter().collect();
+            let substituted: String = unleet[start..end].iter().collect();
+            let reversed: String = exact.chars().rev().collect();
+            let capitalized = chars[start..end].iter().any(|c| c.is_uppercase());
+            let variations = if capitalized { 2.0 } else { 1.0 };
+
+            let common = |word: &str| COMMON_PASSWORDS.iter().position(|p| *p == word);
+            let rank = common(&exact)
+                .map(|rank| rank as f64 + 1.0)
+                .or_else(|| common(&substituted).map(|rank| (rank as f64 + 1.0) * 2.0))
+                .or_else(|| common(&reversed).map(|rank| (rank as f64 + 1.0) * 2.0));
+            if let Some(rank) = rank {
+                matches.push(Match {
+                    start,
+                    end,
+                    guesses: rank * variations,
+                    pattern: Pattern::Common,
+                });
+            }
+
+            if personal.iter().any(|p| *p == exact || *p == substituted) {
+                matches.push(Match {
+                    start,
+                    end,
+                    guesses: 10.0 * variations,
+                    pattern: Pattern::Personal,
+                });
+            }
+
+            let len = (end - start) as f64;
+            if end - start >= 4
+                && KEYBOARD_ROWS
+                    .iter()
+                    .any(|row| row.contains(exact.as_str()) || row.contains(reversed.as_str()))
+            {
+                matches.push(Match {
+                    start,
+                    end,
+                    guesses: 20.0 * len,
+                    pattern: Pattern::Keyboard,
+                });
+            }
+
+            if is_sequence(&lower[start..end]) {
+                matches.push(Match {
+                    start,
+                    end,
+                    guesses: 4.0 * len,
+                    pattern: Pattern::Sequence,
+                });
+            }
+
+            if lower[start..end].iter().all(|c| *c == lower[start]) {
+                matches.push(Match {
+                    start,
+                    end,
+                    guesses: 10.0 * len,
+                    pattern: Pattern::Repeat,
+                });
+            }
+
+            if end - start == 4 && (1900..2040).contains(&exact.parse::<u32>().unwrap_or(0)) {
+                matches.push(Match {
+                    start,
+                    end,
+                    guesses: 140.0,
+                    pattern: Pattern::Year,
+                });
+            }
+        }
+    }
+
+    // Cheapest cover of the first i characters, in log10 of guesses
+    let brute_force = (pool_size(password) as f64).log10();
+    let mut best: Vec<(f64, Vec<Pattern>)> = vec![(0.0, vec![]); n + 1];
+    for end in 1..=n {
+        let (previous, patterns) = &best[end - 1];
+        let mut candidate = (previous + brute_force, patterns.clone());
+        for m in matches.iter().filter(|m| m.end == end) {
+            let (previous, patterns) = &best[m.start];
+            let guesses = previous + m.guesses.log10();
+            if guesses < candidate.0 {
+                let mut patterns = patterns.clone();
+                patterns.push(m.pattern);
+                candidate = (guesses, patterns);
+            }
+        }
+        best[end] = candidate;
+    }
+
+    let (guesses_log10, patterns) = best.pop().unwrap_or_default();
+    let score = match guesses_log10 {
+        g if g < 3.0 => 0,
+        g if g < 6.0 => 1,
+        g if g < 8.0 => 2,
+        g if g < 10.0 => 3,
+        _ => 4,
+    };
+
+    let warning = match patterns.iter().min() {
+        _ if score >= 3 => None,
+        Some(Pattern::Common) => Some("This is similar to a commonly used password"),
+        Some(Pattern::Personal) => Some("Passwords based on your name or email are easy to guess"),
+        Some(Pattern::Keyboard) => Some("Straight rows of keys are easy to guess"),
+        Some(Pattern::Sequence) => Some("Sequences like abc or 6543 are easy to guess"),
+        Some(Pattern::Repeat) => Some("Repeats like aaa are easy to guess"),
+        Some(Pattern::Year) => Some("Recent years are easy to guess"),
+        None if n < 8 => Some("Short passwords are easy to guess"),
+        None => None,
+    };
+    let suggestions = if score >= 3 {
+        vec![]
+    } else {
+        vec![
+            "Use a few words, avoid common phrases",
+            "No need for symbols, digits, or uppercase letters",
+            "Add another word or two. Uncommon words are better.",
+        ]
+    };
+
+    Estimate {
+        guesses_log10,
+        score,
+        warning,
+        suggestions,
+    }
+}
+
+/// Characters to try for each unknown position, from the classes used in `password`
+fn pool_size(password: &str) -> usize {
+    let mut size = 0;
+    if password.chars().any(|c| c.is_ascii_lowercase()) {
+        size += 26;
+    }
+    if password.chars().any(|c| c.is_ascii_uppercase()) {
+        size += 26;
+    }
+    if password.chars().any(|c| c.is_ascii_digit()) {
+        size += 10;
+    }
+    if password
+        .chars()
+        .any(|c| c.is_ascii_punctuation() || c == ' ')
+    {
+        size += 33;
+    }
+    if !password.is_ascii() {
+        size += 100;
+    }
+    size.max(10)
+}
+
+/// Whether `chars` go up or down by one each time, like `abcd` or `9876`
+fn is_sequence(chars: &[char]) -> bool {
+    let steps: Vec<i64> = chars
+        .windows(2)
+        .map(|pair| pair[1] as i64 - pair[0] as i64)
+        .collect();
+    !steps.is_empty()
+        && steps
+            .iter()
+            .all(|step| *step == steps[0] && step.abs() == 1)
+}
+
+/// Undo common letter substitutions, like `p@ssw0rd`
+fn unleet(c: char) -> char {
+    match c {
+        '@' | '4' => 'a',
+        '3' => 'e',
+        '1' | '!' => 'i',
+        '0' => 'o',
+        '$' | '5' => 's',
+        '7' => 't',
+        c => c,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> PasswordPolicy {
+        PasswordPolicy {
+            min_length: 8,
+            max_length: 128,
+            min_classes: 0,
+            min_score: 2,
+            history_size: 5,
+            breached_passwords_dir: None,
+        }
+    }
+
+    fn codes(response: &PasswordStrengthResponse) -> Vec<&str> {
+        response
+            .errors
+            .iter()
+            .map(|error| error.code.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn common_passwords_are_weak() {
+        for password in ["password", "p@ssw0rd", "drowssap", "Password1", "qwerty123"] {
+            let estimate = estimate(password, &[]);
+            assert!(estimate.score <= 1, "{password}: {}", estimate.score);
+            assert_eq!(
+                estimate.warning,
+                Some("This is similar to a commonly used password"),
+                "{password}"
+            );
+        }
+    }
+
+    #[test]
+    fn patterns_are_cheaper_than_brute_force() {
+        let random = estimate("kxq7tbwz", &[]).guesses_log10;
+        for password in ["asdfghjk", "abcdefgh", "aaaaaaaa", "19871988"] {
+            let estimate = estimate(password, &[]);
+            assert!(estimate.guesses_log10 < random, "{password}");
+            assert!(estimate.score <= 1, "{password}");
+        }
+    }
+
+    #[test]
+    fn passphrases_are_strong() {
+        let estimate = estimate("copper lantern harbor", &[]);
+        assert_eq!(estimate.score, 4);
+        assert_eq!(estimate.warning, None);
+        assert!(estimate.suggestions.is_empty());
+    }
+
+    #[test]
+    fn personal_information_is_refused() {
+        let user_inputs = vec!["ada.lovelace@example.com".to_string()];
+        let response = policy().check("Lovelace#1815!", &user_inputs);
+
+        assert!(codes(&response).contains(&"contains_personal_info"));
+        assert!(!response.valid);
+    }
+
+    #[test]
+    fn check_lists_every_violation() {
+        let policy = PasswordPolicy {
+            min_classes: 3,
+            ..policy()
+        };
+
+        let response = policy.check("abc", &[]);
+        assert_eq!(
+            codes(&response),
+            ["too_short", "missing_character_classes", "too_weak"]
+        );
+        assert!(!response.valid);
+
+        let response = policy.check("Copper lantern harbor 7", &[]);
+        assert!(response.errors.is_empty());
+        assert!(response.valid);
+    }
+
+    #[test]
+    fn too_long_passwords_are_not_scored() {
+        let response = policy().check(&"a".repeat(2 * 1024 * 1024), &[]);
+
+        assert_eq!(codes(&response), ["too_long"]);
+        assert_eq!(response.score, 0);
+        assert!(!response.valid);
+    }
+
+    #[test]
+    fn max_length_counts_bytes() {
+        let policy = PasswordPolicy {
+            max_length: BCRYPT_MAX_PASSWORD_BYTES,
+            ..policy()
+        };
+
+        // 36 characters, 72 bytes
+        assert!(!codes(&policy.check(&"é".repeat(36), &[])).contains(&"too_long"));
+        // 37 characters, 74 bytes, of which bcrypt would ignore the last two
+        assert_eq!(codes(&policy.check(&"é".repeat(37), &[])), ["too_long"]);
+    }
+
+    #[test]
+    fn passwords_longer_than_a_match_are_estimated() {
+        let password: String = "correct horse battery staple ".repeat(40);
+        let estimate = estimate(&password, &[]);
+
+        assert_eq!(estimate.score, 4);
+    }
+}
@@ -0,0 +1,421 @@
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::PayloadError;
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::web::{Bytes, BytesMut};
+use actix_web::{Error, HttpMessage, HttpResponse, ResponseError};
+use futures::future::{ready, LocalBoxFuture, Ready};
+use futures::{Stream, StreamExt};
+use log::warn;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::auth::decode_refresh_token;
+use crate::db::DbPool;
+use crate::entity::general::RateLimitBucket;
+use crate::utils::{client_ip, normalize_email};
+use crate::MyError;
+
+/// Requests are limited per route on these keys, a key missing from a request is not limited
+const DEFAULT_RULES: &[(&str, RateLimitKey, u32, u64)] = &[
+    ("/login", RateLimitKey::Ip, 20, 60),
+    ("/login", RateLimitKey::Email, 5, 60),
+    ("/register", RateLimitKey::Ip, 10, 3600),
+    ("/register", RateLimitKey::Email, 3, 3600),
+    ("/forgot", RateLimitKey::Ip, 10, 3600),
+    ("/forgot", RateLimitKey::Email, 3, 3600),
+    ("/login/link", RateLimitKey::Ip, 10, 3600),
+    ("/login/link", RateLimitKey::Email, 3, 3600),
+    ("/verify/resend", RateLimitKey::Ip, 10, 3600),
+    ("/verify/resend", RateLimitKey::Email, 3, 3600),
+    ("/refresh", RateLimitKey::Ip, 120, 60),
+    ("/refresh", RateLimitKey::Client, 60, 60),
+    ("/password/strength", RateLimitKey::Ip, 30, 60),
+    ("/reauthenticate", RateLimitKey::Ip, 10, 60),
+    ("/user/password", RateLimitKey::Ip, 10, 60),
+];
+
+/// Bodies read to find the `email` of a request, larger ones are refused
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// Database calls between two deletions of idle buckets
+const PRUNE_EVERY: u64 = 1000;
+
+/// What requests are counted by
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitKey {
+    /// Address of the client, see `client_ip`
+    Ip,
+    /// Normalized `email` of the JSON body
+    Email,
+    /// User of a valid `refresh_token` cookie, which the server signed, else the client IP
+    Client,
+}
+
+impl RateLimitKey {
+    fn name(&self) -> &'static str {
+        match self {
+            RateLimitKey::Ip => "ip",
+            RateLimitKey::Email => "email",
+            RateLimitKey::Client => "client",
+        }
+    }
+}
+
+/// At most `capacity` requests in a burst, refilled evenly over `period_secs`
+#[derive(Debug, Clone)]
+pub struct RateLimitRule {
+    pub path: String,
+    pub key: RateLimitKey,
+    pub capacity: u32,
+    pub period_secs: u64,
+}
+
+impl RateLimitRule {
+    fn per_second(&self) -> f64 {
+        self.capacity as f64 / self.period_secs as f64
+    }
+}
+
+/// Outcome of the most restrictive rule of a request, sent as `RateLimit-*` headers
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimitStatus {
+    pub limit: u32,
+    pub remaining: u32,
+    /// Seconds until the bucket is full again
+    pub reset: i64,
+    /// Seconds until the next request is allowed, when this one was refused
+    pub retry_after: Option<i64>,
+}
+
+/// Token buckets stored in Postgres, so every instance shares them.
+/// Each instance mirrors them in memory and refuses requests to an empty bucket without asking
+/// the database, which is then only queried when a request may be allowed.
+pub struct RateLimiter {
+    rules: Vec<RateLimitRule>,
+    local: Mutex<HashMap<String, LocalBucket>>,
+    calls: AtomicU64,
+    pool: DbPool,
+}
+
+struct LocalBucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl RateLimiter {
+    /// Rules are the defaults, each can be replaced with `RATE_LIMIT_<ROUTE>_<KEY>` set to
+    /// `<capacity>/<seconds>` or `off`, like `RATE_LIMIT_LOGIN_EMAIL=5/60`.
+    pub fn from_env(pool: DbPool) -> Self {
+        let rules = DEFAULT_RULES
+            .iter()
+            .filter_map(|(path, key, capacity, period_secs)| {
+                let name = format!(
+                    "RATE_LIMIT_{}_{}",
+                    path.trim_start_matches('/')
+                        .replace('/', "_")
+                        .to_uppercase(),
+                    key.name().to_uppercase()
+                );
+                let (capacity, period_secs) = match std::env::var(&name) {
+                    Ok(value) if value.trim() == "off" => return None,
+                    Ok(value) => value
+                        .split_once('/')
+                        .and_then(|(capacity, secs)| {
+                            Some((capacity.trim().parse().ok()?, secs.trim().parse().ok()?))
+                        })
+                        .filter(|(capacity, secs)| *capacity > 0 && *secs > 0)
+                        .unwrap_or_else(|| {
+                            warn!("{name}: expected <capacity>/<seconds> or off, got {value}");
+                            (*capacity, *period_secs)
+                        }),
+                    Err(_) => (*capacity, *period_secs),
+                };
+
+                Some(RateLimitRule {
+                    path: path.to_string(),
+                    key: *key,
+                    capacity,
+                    period_secs,
+                })
+            })
+            .collect();
+
+        RateLimiter {
+            rules,
+            local: Mutex::new(HashMap::new()),
+            calls: AtomicU64::new(0),
+            pool,
+        }
+    }
+
+    fn rules_for(&self, path: &str) -> Vec<RateLimitRule> {
+        self.rules
+            .iter()
+            .filter(|rule| rule.path == path)
+            .cloned()
+            .collect()
+    }
+
+    /// Take a token of every rule from the buckets of `keys`.
+    /// Returns the status of the most restrictive rule, refused if any bucket was empty.
+    pub async fn check(
+        &self,
+        rules: &[RateLimitRule],
+        keys: &HashMap<&'static str, String>,
+    ) -> Option<RateLimitStatus> {
+        let mut result: Option<RateLimitStatus> = None;
+        for rule in rules {
+            let key = match keys.get(rule.key.name()) {
+                Some(key) => format!("{}:{}:{}", rule.path, rule.key.name(), key),
+                None => continue,
+            };
+            let status = self.take(rule, key).await;
+
+            result = match result {
+                Some(current) if current.restricts_more_than(&status) => Some(current),
+                _ => Some(status),
+            };
+        }
+
+        result
+    }
+
+    async fn take(&self, rule: &RateLimitRule, key: String) -> RateLimitStatus {
+        let capacity = rule.capacity as f64;
+        let per_second = rule.per_second();
+
+        // Fast path: the bucket was empty at the last call and has not refilled since
+        let local_tokens = self.refill_local(&key, capacity, per_second);
+        if local_tokens < 1.0 {
+            return status(rule, local_tokens, false);
+        }
+
+        if self
+            .calls
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(PRUNE_EVERY)
+        {
+            let longest = self.rules.iter().map(|rule| rule.period_secs).max();
+            let idle = chrono::Duration::seconds(longest.unwrap_or(0) as i64);
+            if let Err(e) = RateLimitBucket::prune(idle, &self.pool).await {
+                warn!("RateLimitBucket::prune: {}", e);
+            }
+        }
+
+        let (tokens, allowed) =
+            match RateLimitBucket::take(key.clone(), capacity, per_second, &self.pool).await {
+                Ok(bucket) => (bucket.tokens, bucket.allowed),
+                // Without the database each instance still limits on its own
+                Err(e) => {
+                    warn!("RateLimitBucket::take {}: {}", key, e);
+                    (local_tokens - 1.0, true)
+                }
+            };
+
+        if let Ok(mut local) = self.local.lock() {
+            if local.len() > 10_000 {
+                local.retain(|_, bucket| bucket.updated_at.elapsed().as_secs() < rule.period_secs);
+            }
+            local.insert(
+                key,
+                LocalBucket {
+                    tokens,
+                    updated_at: Instant::now(),
+                },
+            );
+        }
+
+        status(rule, tokens, allowed)
+    }
+
+    /// Tokens the local mirror of `key` should hold now, a full bucket when it is unknown
+    fn refill_local(&self, key: &str, capacity: f64, per_second: f64) -> f64 {
+        let local = match self.local.lock() {
+            Ok(local) => local,
+            Err(_) => return capacity,
+        };
+
+        match local.get(key) {
+            Some(bucket) => (bucket.tokens
+                + bucket.updated_at.elapsed().as_secs_f64() * per_second)
+                .min(capacity),
+            None => capacity,
+        }
+    }
+}
+
+impl RateLimitStatus {
+    /// Refused before allowed, then the longest wait or the fewest remaining requests
+    fn restricts_more_than(&self, other: &RateLimitStatus) -> bool {
+        match (self.retry_after, other.retry_after) {
+            (Some(retry_after), Some(other_retry_after)) => retry_after >= other_retry_after,
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (None, None) => self.remaining <= other.remaining,
+        }
+    }
+}
+
+fn status(rule: &RateLimitRule, tokens: f64, allowed: bool) -> RateLimitStatus {
+    let per_second = rule.per_second();
+    RateLimitStatus {
+        limit: rule.capacity,
+        remaining: tokens.max(0.0).floor() as u32,
+        reset: ((rule.capacity as f64 - tokens) / per_second)
+            .ceil()
+            .max(0.0) as i64,
+        retry_after: (!allowed).then(|| ((1.0 - tokens) / per_second).ceil().max(1.0) as i64),
+    }
+}
+
+/// Middleware applying the rules of a `RateLimiter` to the routes of a scope.
+/// Refused requests get `429` with `Retry-After`, every limited response the `RateLimit-Limit`,
+/// `RateLimit-Remaining` and `RateLimit-Reset` headers.
+pub struct RateLimit {
+    limiter: Arc<RateLimiter>,
+}
+
+impl RateLimit {
+    pub fn new(limiter: Arc<RateLimiter>) -> Self {
+        RateLimit { limiter }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware {
+            service: Rc::new(service),
+            limiter: self.limiter.clone(),
+        }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<S>,
+    limiter: Arc<RateLimiter>,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut request: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let limiter = self.limiter.clone();
+
+        Box::pin(async move {
+            let rules = limiter.rules_for(request.match_info().unprocessed());
+            if rules.is_empty() {
+                return Ok(service.call(request).await?.map_into_left_body());
+            }
+
+            let mut keys = HashMap::new();
+            let address = client_ip(request.parts_mut().0).map(|address| address.to_string());
+            if let Some(address) = &address {
+                keys.insert(RateLimitKey::Ip.name(), address.clone());
+            }
+            // Headers sent by anyone could spread requests over countless buckets
+            let session = request
+                .cookie("refresh_token")
+                .and_then(|cookie| decode_refresh_token(cookie.value().to_string()).ok());
+            if let Some(client) = session.or(address) {
+                keys.insert(RateLimitKey::Client.name(), client);
+            }
+            if rules.iter().any(|rule| rule.key == RateLimitKey::Email) {
+                match read_email(&mut request).await {
+                    Ok(Some(email)) => {
+                        keys.insert(RateLimitKey::Email.name(), normalize_email(&email));
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        let response = e.error_response();
+                        return Ok(request.into_response(response).map_into_right_body());
+                    }
+                }
+            }
+
+            let status = limiter.check(&rules, &keys).await;
+            if let Some(RateLimitStatus {
+                retry_after: Some(retry_after),
+                ..
+            }) = status
+            {
+                let mut response = MyError::RateLimited { retry_after }.error_response();
+                add_headers(&mut response, status);
+                return Ok(request.into_response(response).map_into_right_body());
+            }
+
+            let mut response = service.call(request).await?;
+            add_headers(response.response_mut(), status);
+            Ok(response.map_into_left_body())
+        })
+    }
+}
+
+fn add_headers<B>(response: &mut HttpResponse<B>, status: Option<RateLimitStatus>) {
+    if let Some(status) = status {
+        let headers = response.headers_mut();
+        headers.insert(
+            HeaderName::from_static("ratelimit-limit"),
+            HeaderValue::from(status.limit),
+        );
+        headers.insert(
+            HeaderName::from_static("ratelimit-remaining"),
+            HeaderValue::from(status.remaining),
+        );
+        headers.insert(
+            HeaderName::from_static("ratelimit-reset"),
+            HeaderValue::from(status.reset),
+        );
+    }
+}
+
+/// The `email` member of a JSON body, which is put back for the handler
+async fn read_email(request: &mut ServiceRequest) -> Result<Option<String>, MyError> {
+    let mut payload = request.take_payload();
+    let mut body = BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(|e| MyError::Validation {
+            desc: e.to_string(),
+        })?;
+        if body.len() + chunk.len() > MAX_BODY_SIZE {
+            return Err(MyError::Validation {
+                desc: "Request body is too large".to_string(),
+            });
+        }
+        body.extend_from_slice(&chunk);
+    }
+    let body = body.freeze();
+
+    let email = serde_json::from_slice::<serde_json::Value>(&body)
+        .ok()
+        .and_then(|value| value.get("email")?.as_str().map(str::to_string));
+
+    let stream: Pin<Box<dyn Stream<Item = Result<Bytes, PayloadError>>>> =
+        Box::pin(futures::stream::once(ready(Ok(body))));
+    request.set_payload(Payload::from(stream));
+
+    Ok(email)
+}
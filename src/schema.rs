@@ -1,12 +1,112 @@
 table! {
-    reset (token) {
+    email_outbox (id) {
+        id -> Uuid,
+        recipient -> Varchar,
+        subject -> Varchar,
+        html_body -> Text,
+        text_body -> Text,
+        attempts -> Int4,
+        next_attempt_at -> Timestamp,
+        last_error -> Nullable<Varchar>,
+        created_at -> Timestamp,
+        sent_at -> Nullable<Timestamp>,
+        dead_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    email_verification (token_hash) {
+        token_hash -> Varchar,
+        user_id -> Uuid,
+        email -> Varchar,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    login_failure (key) {
+        key -> Varchar,
+        failures -> Int4,
+        last_failure_at -> Timestamp,
+        blocked_until -> Nullable<Timestamp>,
+        locked_at -> Nullable<Timestamp>,
+        unlock_token_hash -> Nullable<Varchar>,
+    }
+}
+
+table! {
+    login_link (token) {
         token -> Varchar,
         email -> Varchar,
+        state -> Varchar,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    password_history (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        password -> Varchar,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    pending_login (code) {
+        code -> Varchar,
+        state -> Varchar,
+        user_id -> Nullable<Uuid>,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+        approved_at -> Nullable<Timestamp>,
+        claimed_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    rate_limit_bucket (key) {
+        key -> Varchar,
+        tokens -> Float8,
+        allowed -> Bool,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    reset (token_hash) {
+        token_hash -> Varchar,
+        email -> Varchar,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    trusted_device (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        user_agent -> Varchar,
+        created_at -> Timestamp,
+        last_seen_at -> Timestamp,
+        expires_at -> Timestamp,
+    }
+}
+
+table! {
+    used_challenge (jti) {
+        jti -> Varchar,
+        expires_at -> Timestamp,
     }
 }
 
 table! {
-    user_token (user_id) {
+    user_token (token) {
         user_id -> Uuid,
         token -> Varchar,
         created_at -> Timestamp,
@@ -21,13 +121,29 @@ table! {
         last_name -> Varchar,
         email -> Varchar,
         password -> Varchar,
+        email_verified_at -> Nullable<Timestamp>,
+        email_normalized -> Nullable<Varchar>,
+        locale -> Nullable<Varchar>,
     }
 }
 
+joinable!(email_verification -> users (user_id));
+joinable!(password_history -> users (user_id));
+joinable!(pending_login -> users (user_id));
+joinable!(trusted_device -> users (user_id));
 joinable!(user_token -> users (user_id));
 
 allow_tables_to_appear_in_same_query!(
+    email_outbox,
+    email_verification,
+    login_failure,
+    login_link,
+    password_history,
+    pending_login,
+    rate_limit_bucket,
     reset,
+    trusted_device,
+    used_challenge,
     user_token,
     users,
 );
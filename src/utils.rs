@@ -1,4 +1,10 @@
-// use log::info;
+use crate::MyError;
+use actix_web::HttpRequest;
+use log::info;
+use rand::distributions::{Alphanumeric, DistString};
+use sha2::{Digest, Sha256};
+use std::net::IpAddr;
+use unicode_normalization::UnicodeNormalization;
 
 pub fn initiate_logging() {
     std::env::set_var("RUST_LOG", "debug, actix_web=debug");
@@ -14,3 +20,92 @@ pub fn initiate_logging() {
 
     env_logger::init();
 }
+
+/// Canonical form of an email used to compare addresses: trimmed, Unicode NFC and case-folded
+pub fn normalize_email(email: &str) -> String {
+    let composed = email.trim().nfc().collect::<String>();
+    caseless::default_case_fold_str(&composed).nfc().collect()
+}
+
+/// Address of the client which sent `request`.
+/// `X-Forwarded-For` is only believed when the peer is listed in the comma-separated
+/// `TRUSTED_PROXIES`, then the client is the last address added by an untrusted hop.
+pub fn client_ip(request: &HttpRequest) -> Option<IpAddr> {
+    let peer = request.peer_addr()?.ip();
+    let proxies: Vec<IpAddr> = std::env::var("TRUSTED_PROXIES")
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|proxy| proxy.trim().parse().ok())
+        .collect();
+    if !proxies.contains(&peer) {
+        return Some(peer);
+    }
+
+    let forwarded: Vec<IpAddr> = request
+        .headers()
+        .get_all("x-forwarded-for")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|address| address.trim().parse())
+        .collect::<Result<_, _>>()
+        .unwrap_or_default();
+
+    Some(
+        forwarded
+            .iter()
+            .rev()
+            .find(|address| !proxies.contains(address))
+            .or_else(|| forwarded.first())
+            .copied()
+            .unwrap_or(peer),
+    )
+}
+
+/// Random alphanumeric string, used for one-time tokens
+pub fn random_token(length: usize) -> String {
+    Alphanumeric.sample_string(&mut rand::thread_rng(), length)
+}
+
+/// Hex SHA-256 of a one-time token, the form in which it is stored
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_case_and_whitespace() {
+        assert_eq!(
+            normalize_email(" Jane.Doe@Example.COM\t"),
+            "jane.doe@example.com"
+        );
+        assert_eq!(
+            normalize_email("jane.doe@example.com"),
+            "jane.doe@example.com"
+        );
+    }
+
+    #[test]
+    fn normalizes_unicode() {
+        // composed and decomposed accents
+        assert_eq!(
+            normalize_email("Jos\u{e9}@example.com"),
+            "jos\u{e9}@example.com"
+        );
+        assert_eq!(
+            normalize_email("Jose\u{301}@example.com"),
+            "jos\u{e9}@example.com"
+        );
+        // full case folding, beyond lowercase
+        assert_eq!(
+            normalize_email("STRASSE@example.com"),
+            normalize_email("stra\u{df}e@example.com")
+        );
+        assert_eq!(
+            normalize_email("\u{212a}elvin@example.com"),
+            "kelvin@example.com"
+        );
+    }
+}